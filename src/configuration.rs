@@ -1,20 +1,28 @@
 use crate::home_assistant::configuration::{
-    create_ha_configuration, HaConfiguration, HaEntity, HA_BACKGROUND_BLURRED, HA_FRIENDLY_NAME,
-    HA_HAND_RAISED, HA_ICON_OFF, HA_ICON_ON, HA_ID, HA_IN_A_MEETING, HA_LONG_LIVE_TOKEN, HA_MUTED,
-    HA_RECORDING, HA_SHARING, HA_UNREAD_MESSAGES, HA_URL, HA_VIDEO_ON, HOME_ASSISTANT,
+    create_ha_configuration, HaConfiguration, HaEntity, HA_BACKGROUND_BLURRED, HA_COMMAND_ENTITY,
+    HA_COMMAND_EVENT, HA_EVENT_FIELDS, HA_EVENT_TYPE, HA_FRIENDLY_NAME, HA_HAND_RAISED,
+    HA_ICON_OFF, HA_ICON_ON, HA_ID, HA_IN_A_MEETING, HA_LONG_LIVE_TOKEN, HA_MUTED, HA_RECORDING,
+    HA_SHARING, HA_UNREAD_MESSAGES, HA_URL, HA_VIDEO_ON, HA_WEBSOCKET, HOME_ASSISTANT,
 };
 use crate::mqtt::configuration::{
-    create_mqtt_configuration, MqttConfiguration, MQTT, MQTT_BACKGROUND_BLURRED, MQTT_ENTITIES,
-    MQTT_HAND_RAISED, MQTT_MEETING, MQTT_MUTED, MQTT_PASSWORD, MQTT_PORT, MQTT_PORT_DEFAULT,
-    MQTT_RECORDING, MQTT_SHARING, MQTT_TOPIC, MQTT_UNREAD_MESSAGES, MQTT_URL, MQTT_USERNAME,
-    MQTT_VIDEO,
+    create_mqtt_configuration, MqttConfiguration, PublishMode, MQTT, MQTT_AVAILABILITY_TOPIC,
+    MQTT_BACKGROUND_BLURRED, MQTT_CA_FILE, MQTT_CLIENT_CERTIFICATE, MQTT_CLIENT_ID,
+    MQTT_CLIENT_KEY, MQTT_COMMAND_TOPIC, MQTT_DISCOVERY, MQTT_DISCOVERY_PREFIX, MQTT_ENTITIES,
+    MQTT_HAND_RAISED, MQTT_INSECURE, MQTT_MEETING, MQTT_MUTED, MQTT_PASSWORD, MQTT_PAYLOAD_OFF,
+    MQTT_PAYLOAD_ON, MQTT_PAYLOAD_UNKNOWN, MQTT_PERMISSIONS, MQTT_PORT, MQTT_PORT_DEFAULT,
+    MQTT_PUBLISH_MODE, MQTT_RECORDING, MQTT_RESULT_TOPIC, MQTT_SHARING, MQTT_TOPIC,
+    MQTT_UNREAD_MESSAGES, MQTT_URL, MQTT_USERNAME, MQTT_VIDEO,
 };
 use crate::teams_ws::configuration::{
-    create_teams_configuration, TeamsConfiguration, TEAMS, TEAMS_API_TOKEN, TEAMS_URL,
+    create_teams_configuration, TeamsConfiguration, TEAMS, TEAMS_API_TOKEN, TEAMS_APP,
+    TEAMS_APP_VERSION, TEAMS_DEVICE, TEAMS_MANUFACTURER, TEAMS_PING_INTERVAL,
+    TEAMS_PING_INTERVAL_DEFAULT, TEAMS_PING_TIMEOUT, TEAMS_PING_TIMEOUT_DEFAULT,
+    TEAMS_PROTOCOL_VERSION, TEAMS_URL,
 };
 use crate::utils::{decrypt_if_needed, encrypt};
 use ini::Ini;
 use log::{error, info};
+use std::collections::HashMap;
 use std::fs;
 
 const GENERAL: &str = "General";
@@ -22,12 +30,21 @@ const GEN_CONF_VERSION: &str = "Configuration Version";
 const GEN_CONF_VERSION_CURRENT: u32 = 1;
 // Anything below this will result in copying the configuration as a backup as there are breaking changes
 const GEN_CONF_VERSION_CUTOFF: u32 = 1;
-const INI_FILE_NAME: &str = "conf.ini";
+pub const INI_FILE_NAME: &str = "conf.ini";
+
+// Additional instances are configured in sections named after the main one, ex:
+// `[Home Assistant - Office]`, they start from the main instance's configuration
+const INSTANCE_SEPARATOR: &str = " - ";
 
 pub struct Configuration {
     pub ha: HaConfiguration,
     pub teams: TeamsConfiguration,
     pub mqtt: MqttConfiguration,
+    // by section name
+    pub additional_ha: Vec<(String, HaConfiguration)>,
+    pub additional_mqtt: Vec<(String, MqttConfiguration)>,
+    // the keys set in each additional section, the others keep following the main instance
+    additional_keys: HashMap<String, Vec<String>>,
     pub version: u32,
 }
 
@@ -51,6 +68,57 @@ fn load_entity(ha_entity: &mut HaEntity, config_name: &str, config_value: String
     }
 }
 
+fn load_ha(ha: &mut HaConfiguration, config_name: &str, config_value: &str) {
+    match config_name {
+        HA_LONG_LIVE_TOKEN => ha.long_live_token = decrypt_if_needed(config_value),
+        HA_URL => ha.url = config_value.to_string(),
+        HA_WEBSOCKET => ha.websocket = config_value.parse().unwrap_or(false),
+        HA_COMMAND_EVENT => ha.command_event = config_value.to_string(),
+        HA_EVENT_TYPE => ha.event_type = config_value.to_string(),
+        HA_EVENT_FIELDS => ha.set_event_fields(config_value),
+        _ => {
+            if let Some(command) = config_name.strip_prefix(HA_COMMAND_ENTITY) {
+                ha.command_entities
+                    .insert(command.trim().to_lowercase(), config_value.to_string());
+            }
+            /* We just ignore incorrect configs */
+        }
+    }
+}
+
+fn load_mqtt(mqtt: &mut MqttConfiguration, config_name: &str, config_value: &str) {
+    match config_name {
+        MQTT_URL => mqtt.set_url(config_value.to_string()),
+        MQTT_PORT => mqtt.port = config_value.parse().unwrap_or(MQTT_PORT_DEFAULT),
+        MQTT_TOPIC => mqtt.topic = config_value.to_string(),
+        MQTT_USERNAME => mqtt.username = config_value.to_string(),
+        MQTT_PASSWORD => mqtt.password = decrypt_if_needed(config_value),
+        MQTT_CLIENT_ID => mqtt.client_id = config_value.to_string(),
+        MQTT_CA_FILE => mqtt.ca_file = config_value.to_string(),
+        MQTT_CLIENT_CERTIFICATE => mqtt.client_certificate = config_value.to_string(),
+        MQTT_CLIENT_KEY => mqtt.client_key = config_value.to_string(),
+        MQTT_INSECURE => mqtt.insecure = config_value.parse().unwrap_or(false),
+        MQTT_COMMAND_TOPIC => mqtt.command_topic = config_value.to_string(),
+        MQTT_RESULT_TOPIC => mqtt.result_topic = config_value.to_string(),
+        MQTT_AVAILABILITY_TOPIC => mqtt.availability_topic = config_value.to_string(),
+        MQTT_PUBLISH_MODE => {
+            mqtt.publish_mode = PublishMode::parse(config_value).unwrap_or(PublishMode::Json)
+        }
+        MQTT_PAYLOAD_ON => mqtt.payload_on = config_value.to_string(),
+        MQTT_PAYLOAD_OFF => mqtt.payload_off = config_value.to_string(),
+        MQTT_PAYLOAD_UNKNOWN => mqtt.payload_unknown = config_value.to_string(),
+        MQTT_DISCOVERY => mqtt.discovery = config_value.parse().unwrap_or(true),
+        MQTT_DISCOVERY_PREFIX => mqtt.discovery_prefix = config_value.to_string(),
+        _ => { /* We just ignore incorrect configs */ }
+    }
+}
+
+fn is_instance_of(section: &str, main_section: &str) -> bool {
+    section
+        .strip_prefix(main_section)
+        .is_some_and(|name| name.starts_with(INSTANCE_SEPARATOR))
+}
+
 fn load_configuration(conf: &mut Configuration) {
     let i = Ini::load_from_file(INI_FILE_NAME).unwrap_or_else(|err| {
         info!(
@@ -73,11 +141,7 @@ fn load_configuration(conf: &mut Configuration) {
                     GEN_CONF_VERSION => conf.version = v.parse::<u32>().unwrap_or(0),
                     &_ => {}
                 },
-                Some(HOME_ASSISTANT) => match k {
-                    HA_LONG_LIVE_TOKEN => conf.ha.long_live_token = decrypt_if_needed(v),
-                    HA_URL => conf.ha.url = v.to_string(),
-                    _ => { /* We just ignore incorrect configs */ }
-                },
+                Some(HOME_ASSISTANT) => load_ha(&mut conf.ha, k, v),
                 Some(HA_MUTED) => load_entity(&mut conf.ha.entities.is_muted, k, v_string),
                 Some(HA_VIDEO_ON) => load_entity(&mut conf.ha.entities.is_video_on, k, v_string),
                 Some(HA_HAND_RAISED) => {
@@ -99,16 +163,25 @@ fn load_configuration(conf: &mut Configuration) {
                 Some(TEAMS) => match k {
                     TEAMS_URL => conf.teams.url = v.to_string(),
                     TEAMS_API_TOKEN => conf.teams.api_token = decrypt_if_needed(v),
+                    TEAMS_MANUFACTURER => conf.teams.manufacturer = v.to_string(),
+                    TEAMS_DEVICE => conf.teams.device = v.to_string(),
+                    TEAMS_APP => conf.teams.app = v.to_string(),
+                    TEAMS_APP_VERSION => conf.teams.app_version = v.to_string(),
+                    TEAMS_PROTOCOL_VERSION => conf.teams.protocol_version = v.to_string(),
+                    TEAMS_PING_INTERVAL => {
+                        conf.teams.ping_interval = v.parse().unwrap_or(TEAMS_PING_INTERVAL_DEFAULT)
+                    }
+                    // Teams cannot answer in no time, so 0 would drop every connection
+                    TEAMS_PING_TIMEOUT => {
+                        conf.teams.ping_timeout = v
+                            .parse()
+                            .ok()
+                            .filter(|timeout| *timeout > 0)
+                            .unwrap_or(TEAMS_PING_TIMEOUT_DEFAULT)
+                    }
                     _ => { /* We just ignore incorrect configs */ }
                 },
-                Some(MQTT) => match k {
-                    MQTT_URL => conf.mqtt.set_url(v.to_string()),
-                    MQTT_PORT => conf.mqtt.port = v.parse().unwrap_or(MQTT_PORT_DEFAULT),
-                    MQTT_TOPIC => conf.mqtt.topic = v.to_string(),
-                    MQTT_USERNAME => conf.mqtt.username = v.to_string(),
-                    MQTT_PASSWORD => conf.mqtt.password = decrypt_if_needed(v),
-                    _ => { /* We just ignore incorrect configs */ }
-                },
+                Some(MQTT) => load_mqtt(&mut conf.mqtt, k, v),
                 Some(MQTT_ENTITIES) => match k {
                     MQTT_MUTED => conf.mqtt.mqtt_entities.muted = v.to_string(),
                     MQTT_VIDEO => conf.mqtt.mqtt_entities.video = v.to_string(),
@@ -120,6 +193,7 @@ fn load_configuration(conf: &mut Configuration) {
                     }
                     MQTT_SHARING => conf.mqtt.mqtt_entities.sharing = v.to_string(),
                     MQTT_UNREAD_MESSAGES => conf.mqtt.mqtt_entities.unread_messages = v.to_string(),
+                    MQTT_PERMISSIONS => conf.mqtt.mqtt_entities.permissions = v.to_string(),
                     _ => { /* We just ignore incorrect configs */ }
                 },
                 _ => { /* We just ignore incorrect configs */ }
@@ -127,6 +201,34 @@ fn load_configuration(conf: &mut Configuration) {
         }
     }
 
+    // the main instances are fully loaded by now, so additional ones can start from them
+    for (sec, prop) in i.iter() {
+        let Some(section) = sec else { continue };
+        let values: Vec<(&str, &str)> = prop.iter().filter(|(_, v)| !v.is_empty()).collect();
+
+        if is_instance_of(section, HOME_ASSISTANT) {
+            let mut ha = conf.ha.clone();
+            values.iter().for_each(|(k, v)| load_ha(&mut ha, k, v));
+            conf.additional_ha.push((section.to_string(), ha));
+        } else if is_instance_of(section, MQTT) {
+            let mut mqtt = conf.mqtt.clone();
+            values.iter().for_each(|(k, v)| load_mqtt(&mut mqtt, k, v));
+            conf.additional_mqtt.push((section.to_string(), mqtt));
+        } else {
+            continue;
+        }
+
+        let keys = values.iter().map(|(k, _)| k.to_string()).collect();
+        conf.additional_keys.insert(section.to_string(), keys);
+    }
+
+    // the keys are always written, so a missing one means an older configuration
+    let teams_section = i.section(Some(TEAMS));
+    conf.teams.keep_legacy_identity(
+        teams_section.is_some_and(|teams| teams.contains_key(TEAMS_DEVICE)),
+        teams_section.is_some_and(|teams| teams.contains_key(TEAMS_APP_VERSION)),
+    );
+
     if conf.version < GEN_CONF_VERSION_CUTOFF {
         fs::copy(
             INI_FILE_NAME,
@@ -147,6 +249,9 @@ fn create_configuration() -> Configuration {
         ha: create_ha_configuration(),
         teams: create_teams_configuration(),
         mqtt: create_mqtt_configuration(),
+        additional_ha: Vec::new(),
+        additional_mqtt: Vec::new(),
+        additional_keys: HashMap::new(),
         version: 0,
     }
 }
@@ -158,15 +263,93 @@ fn add_entity(ini: &mut Ini, section: &str, ha_entity: &HaEntity) {
         .set(HA_ICON_ON, &ha_entity.icons.on)
         .set(HA_ICON_OFF, &ha_entity.icons.off);
 }
+
+fn add_ha_connection(ini: &mut Ini, section: &str, ha: &HaConfiguration) {
+    ini.with_section(Some(section))
+        .set(HA_URL, &ha.url)
+        .set(HA_LONG_LIVE_TOKEN, encrypt(&ha.long_live_token))
+        .set(HA_WEBSOCKET, ha.websocket.to_string())
+        .set(HA_COMMAND_EVENT, &ha.command_event)
+        .set(HA_EVENT_TYPE, &ha.event_type)
+        .set(HA_EVENT_FIELDS, ha.event_fields_str());
+
+    for (command, entity_id) in &ha.command_entities {
+        ini.with_section(Some(section))
+            .set(format!("{}{}", HA_COMMAND_ENTITY, command), entity_id);
+    }
+}
+
+fn add_mqtt_connection(ini: &mut Ini, section: &str, mqtt: &MqttConfiguration) {
+    ini.with_section(Some(section))
+        .set(MQTT_URL, mqtt.url())
+        .set(MQTT_PORT, &mqtt.port.to_string())
+        .set(MQTT_TOPIC, &mqtt.topic)
+        .set(MQTT_USERNAME, &mqtt.username)
+        .set(MQTT_PASSWORD, encrypt(&mqtt.password))
+        .set(MQTT_CLIENT_ID, &mqtt.client_id)
+        .set(MQTT_CA_FILE, &mqtt.ca_file)
+        .set(MQTT_CLIENT_CERTIFICATE, &mqtt.client_certificate)
+        .set(MQTT_CLIENT_KEY, &mqtt.client_key)
+        .set(MQTT_INSECURE, mqtt.insecure.to_string())
+        .set(MQTT_COMMAND_TOPIC, &mqtt.command_topic)
+        .set(MQTT_RESULT_TOPIC, &mqtt.result_topic)
+        .set(MQTT_AVAILABILITY_TOPIC, &mqtt.availability_topic)
+        .set(MQTT_PUBLISH_MODE, mqtt.publish_mode.as_str())
+        .set(MQTT_PAYLOAD_ON, &mqtt.payload_on)
+        .set(MQTT_PAYLOAD_OFF, &mqtt.payload_off)
+        .set(MQTT_PAYLOAD_UNKNOWN, &mqtt.payload_unknown)
+        .set(MQTT_DISCOVERY, mqtt.discovery.to_string())
+        .set(MQTT_DISCOVERY_PREFIX, &mqtt.discovery_prefix);
+}
+
+// Only the keys that were in the additional section are written back, so that the others keep
+// following the main section when it is edited
+fn add_instance(
+    ini: &mut Ini,
+    section: &str,
+    keys: Option<&Vec<String>>,
+    add_connection: impl FnOnce(&mut Ini),
+) {
+    let mut instance = Ini::new();
+    add_connection(&mut instance);
+
+    // an empty section is still an instance, a copy of the main one
+    let properties = ini
+        .entry(Some(section.to_string()))
+        .or_insert_with(Default::default);
+    let Some(instance) = instance.section(Some(section)) else {
+        return;
+    };
+
+    for key in keys.into_iter().flatten() {
+        if let Some(value) = instance.get(key) {
+            properties.insert(key, value);
+        }
+    }
+}
+
 fn save_ha_configuration(conf: &Configuration) {
     let mut ini = Ini::new();
     ini.with_section(Some(TEAMS))
         .set(TEAMS_URL, &conf.teams.url)
-        .set(TEAMS_API_TOKEN, encrypt(&conf.teams.api_token));
+        .set(TEAMS_API_TOKEN, encrypt(&conf.teams.api_token))
+        .set(TEAMS_MANUFACTURER, &conf.teams.manufacturer)
+        .set(TEAMS_DEVICE, &conf.teams.device)
+        .set(TEAMS_APP, &conf.teams.app)
+        .set(TEAMS_APP_VERSION, &conf.teams.app_version)
+        .set(TEAMS_PROTOCOL_VERSION, &conf.teams.protocol_version)
+        .set(TEAMS_PING_INTERVAL, conf.teams.ping_interval.to_string())
+        .set(TEAMS_PING_TIMEOUT, conf.teams.ping_timeout.to_string());
 
-    ini.with_section(Some(HOME_ASSISTANT))
-        .set(HA_URL, &conf.ha.url)
-        .set(HA_LONG_LIVE_TOKEN, encrypt(&conf.ha.long_live_token));
+    add_ha_connection(&mut ini, HOME_ASSISTANT, &conf.ha);
+    for (section, ha) in &conf.additional_ha {
+        add_instance(
+            &mut ini,
+            section,
+            conf.additional_keys.get(section),
+            |ini| add_ha_connection(ini, section, ha),
+        );
+    }
 
     let ha_entities = &conf.ha.entities;
     add_entity(&mut ini, HA_MUTED, &ha_entities.is_muted);
@@ -186,13 +369,15 @@ fn save_ha_configuration(conf: &Configuration) {
         &ha_entities.has_unread_messages,
     );
 
-    let mqtt = &conf.mqtt;
-    ini.with_section(Some(MQTT))
-        .set(MQTT_URL, mqtt.url())
-        .set(MQTT_PORT, &mqtt.port.to_string())
-        .set(MQTT_TOPIC, &mqtt.topic)
-        .set(MQTT_USERNAME, &mqtt.username)
-        .set(MQTT_PASSWORD, encrypt(&mqtt.password));
+    add_mqtt_connection(&mut ini, MQTT, &conf.mqtt);
+    for (section, mqtt) in &conf.additional_mqtt {
+        add_instance(
+            &mut ini,
+            section,
+            conf.additional_keys.get(section),
+            |ini| add_mqtt_connection(ini, section, mqtt),
+        );
+    }
 
     let mqtt_entities = &conf.mqtt.mqtt_entities;
     ini.with_section(Some(MQTT_ENTITIES))
@@ -203,8 +388,39 @@ fn save_ha_configuration(conf: &Configuration) {
         .set(MQTT_RECORDING, &mqtt_entities.recording)
         .set(MQTT_BACKGROUND_BLURRED, &mqtt_entities.background_blurred)
         .set(MQTT_SHARING, &mqtt_entities.sharing)
-        .set(MQTT_UNREAD_MESSAGES, &mqtt_entities.unread_messages);
+        .set(MQTT_UNREAD_MESSAGES, &mqtt_entities.unread_messages)
+        .set(MQTT_PERMISSIONS, &mqtt_entities.permissions);
     ini.with_section(Some(GENERAL))
         .set(GEN_CONF_VERSION, GEN_CONF_VERSION_CURRENT.to_string());
     ini.write_to_file(INI_FILE_NAME).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::add_instance;
+    use crate::home_assistant::configuration::{HA_LONG_LIVE_TOKEN, HA_URL};
+    use ini::Ini;
+
+    #[test]
+    fn add_instance_will_only_write_keys_of_section() {
+        let mut ini = Ini::new();
+        let keys = vec![HA_URL.to_string()];
+        add_instance(&mut ini, "Home Assistant - Office", Some(&keys), |ini| {
+            ini.with_section(Some("Home Assistant - Office"))
+                .set(HA_URL, "http://office:8123")
+                .set(HA_LONG_LIVE_TOKEN, "main token");
+        });
+
+        let section = ini.section(Some("Home Assistant - Office")).unwrap();
+        assert_eq!(section.get(HA_URL), Some("http://office:8123"));
+        assert_eq!(section.get(HA_LONG_LIVE_TOKEN), None);
+    }
+
+    #[test]
+    fn add_instance_without_keys_will_keep_empty_section() {
+        let mut ini = Ini::new();
+        add_instance(&mut ini, "MQTT - Office", None, |_| {});
+
+        assert!(ini.section(Some("MQTT - Office")).is_some());
+    }
+}
@@ -0,0 +1,256 @@
+use crate::teams_ws::states::TeamsSnapshot;
+use crate::traits::Listener;
+use futures_util::future;
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+const BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+const BACKOFF_MULTIPLIER: u32 = 2;
+// how long the background connections get to close cleanly once the tray is gone
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+// the listeners close at the same time, the rest is left for Teams and the runtime
+const LISTENER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(SHUTDOWN_TIMEOUT.as_secs() - 2);
+
+/// Exponential backoff with jitter, the delay doubles on every failed attempt up to a cap
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempts: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(BACKOFF_INITIAL, BACKOFF_MAX)
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempts: 0,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay_without_jitter();
+        self.attempts = self.attempts.saturating_add(1);
+        // we keep at least half of the delay so that retries never come back too fast
+        delay.mul_f64(rand::random_range(0.5..=1.0))
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    fn delay_without_jitter(&self) -> Duration {
+        let factor = BACKOFF_MULTIPLIER.saturating_pow(self.attempts);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    BackingOff { until: Instant, error: String },
+    Failed(String),
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Connecting => write!(f, "connecting"),
+            ConnectionState::Connected => write!(f, "connected"),
+            ConnectionState::BackingOff { until, error } => write!(
+                f,
+                "retrying in {}s ({})",
+                until.saturating_duration_since(Instant::now()).as_secs(),
+                error
+            ),
+            ConnectionState::Failed(error) => write!(f, "failed ({})", error),
+        }
+    }
+}
+
+/// The state of every connection the application manages (Teams and each listener), by name
+#[derive(Clone, Default)]
+pub struct ConnectionStatus {
+    states: Arc<RwLock<BTreeMap<String, ConnectionState>>>,
+}
+
+impl ConnectionStatus {
+    pub fn set(&self, name: &str, state: ConnectionState) {
+        let mut states = self.states.write().unwrap();
+
+        if states.get(name) != Some(&state) {
+            info!("Connection '{}' is now {}", name, state);
+        }
+
+        states.insert(name.to_string(), state);
+    }
+
+    pub fn summary(&self) -> String {
+        self.states
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, state)| format!("{}: {}", name, state))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// Wraps a listener with its own retry state, a failing listener is retried with a backoff
+/// instead of being hammered on every change coming from Teams
+pub struct ListenerConnection {
+    name: String,
+    listener: Box<dyn Listener>,
+    status: ConnectionStatus,
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+    // what the listener was last told successfully, None when it needs everything again
+    last_sent: Option<TeamsSnapshot>,
+}
+
+impl ListenerConnection {
+    pub fn new(name: &str, listener: Box<dyn Listener>, status: ConnectionStatus) -> Self {
+        status.set(name, ConnectionState::Connecting);
+
+        Self {
+            name: name.to_string(),
+            listener,
+            status,
+            backoff: Backoff::default(),
+            retry_at: None,
+            last_sent: None,
+        }
+    }
+
+    /// Notifies the listener of every new snapshot until the Teams side goes away, only the
+    /// latest snapshot matters so the ones published while the listener was busy are skipped
+    pub async fn run(mut self, mut snapshots: watch::Receiver<TeamsSnapshot>) {
+        // the current state (ex: Teams not available yet) is sent right away
+        snapshots.mark_changed();
+
+        loop {
+            let retry_at = self.retry_at;
+            let retry = async move {
+                match retry_at {
+                    Some(retry_at) => tokio::time::sleep_until(retry_at.into()).await,
+                    None => future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                changed = snapshots.changed() => if changed.is_err() {
+                    break;
+                },
+                _ = retry => {}
+                _ = self.listener.wait_for_resync() => {
+                    info!("Listener '{}' needs every state again", self.name);
+                    self.last_sent = None;
+                }
+            }
+
+            let snapshot = snapshots.borrow_and_update().clone();
+            self.notify(&snapshot).await;
+        }
+
+        // an unreachable server must not hold the application open
+        match tokio::time::timeout(LISTENER_SHUTDOWN_TIMEOUT, self.listener.shutdown()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => error!("Unable to close listener '{}': {}", self.name, error),
+            Err(_) => warn!("Listener '{}' did not close in time", self.name),
+        }
+    }
+
+    fn retry_due(&self) -> bool {
+        self.retry_at
+            .is_some_and(|retry_at| Instant::now() >= retry_at)
+    }
+
+    async fn notify(&mut self, snapshot: &TeamsSnapshot) {
+        // while backing off the latest snapshot is kept for the retry
+        if self.retry_at.is_some() {
+            if !self.retry_due() {
+                return;
+            }
+
+            info!("Reconnecting listener '{}'", self.name);
+            self.status.set(&self.name, ConnectionState::Connecting);
+            self.listener.reconnect();
+            self.retry_at = None;
+        }
+
+        let result = if snapshot.is_available {
+            self.listener
+                .notify_changed(snapshot, self.last_sent.as_ref())
+                .await
+        } else {
+            self.listener.notify_unavailable().await
+        };
+
+        match result {
+            Ok(()) => {
+                self.backoff.reset();
+                self.status.set(&self.name, ConnectionState::Connected);
+                // everything is sent again once Teams is back
+                self.last_sent = snapshot.is_available.then(|| snapshot.clone());
+            }
+            Err(error) => {
+                let delay = self.backoff.next_delay();
+                error!(
+                    "Unable to notify listener '{}', retrying in {:?}: {}",
+                    self.name, delay, error
+                );
+                let until = Instant::now() + delay;
+                self.retry_at = Some(until);
+                self.last_sent = None;
+                self.status.set(
+                    &self.name,
+                    ConnectionState::BackingOff {
+                        until,
+                        error: error.to_string(),
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::Backoff;
+    use std::time::Duration;
+
+    #[test]
+    fn next_delay_will_grow_and_stay_under_cap() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        let delays: Vec<Duration> = (0..6).map(|_| backoff.next_delay()).collect();
+
+        assert!(delays[0] >= Duration::from_millis(500) && delays[0] <= Duration::from_secs(1));
+        assert!(delays[3] >= Duration::from_secs(4) && delays[3] <= Duration::from_secs(8));
+        assert!(delays.iter().all(|delay| *delay <= Duration::from_secs(8)));
+    }
+
+    #[test]
+    fn reset_will_start_over() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
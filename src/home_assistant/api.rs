@@ -1,49 +1,219 @@
+use crate::connection::Backoff;
+use crate::home_assistant::commands::HaCommands;
 use crate::home_assistant::configuration::{HaConfiguration, HaEntity};
-use crate::teams_ws::states::TeamsStates;
+use crate::home_assistant::websocket::{websocket_url, HaWebsocket};
+use crate::teams_ws::commands::CommandBus;
+use crate::teams_ws::states::{TeamsField, TeamsSnapshot};
 use crate::traits::Listener;
-use crate::utils::bool_to_str;
+use crate::utils::{computer_name, flag_to_str};
 use anyhow::anyhow;
 use async_trait::async_trait;
-use futures_util::future::try_join_all;
-use home_assistant_rest::post::StateParams;
+use futures_util::{stream, FutureExt, StreamExt, TryStreamExt};
+use home_assistant_rest::post::{EventParams, StateParams};
 use home_assistant_rest::Client;
 use log::{error, info};
-use serde_json::json;
+use serde_json::{json, Value};
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle};
+
+const HA_STATE_UNAVAILABLE: &str = "unavailable";
+// requests sent to HA at the same time, a slow HA is not flooded when every state is sent
+const MAX_CONCURRENT_REQUESTS: usize = 4;
+// how often we check that HA still has the states we posted, they are lost when it restarts
+const RESYNC_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const HA_STARTED_EVENT: &str = "homeassistant_started";
 
 pub struct HaApi {
     ha_configuration: HaConfiguration,
+    // kept for the lifetime of the listener so that connections are reused
+    client: Client,
+    // the API status is only checked when starting and after a failure
+    check_api_status: bool,
     queried_attributes: bool,
+    // the last state posted for each entity id
+    posted_states: Mutex<HashMap<String, String>>,
+    commands: CommandBus,
+    // shared with the command handling, which only keeps a weak reference to it
+    websocket: Option<Arc<HaWebsocket>>,
+    // HA restarts are told by its websocket, instead of checking the states
+    ha_started: Option<mpsc::UnboundedReceiver<Value>>,
+    // the websocket connects in the background, posting the states never waits for it
+    websocket_connection: Option<JoinHandle<anyhow::Result<WebsocketConnection>>>,
+    websocket_backoff: Backoff,
+    websocket_retry_at: Option<Instant>,
 }
 
+type WebsocketConnection = (Arc<HaWebsocket>, mpsc::UnboundedReceiver<Value>);
+
 impl HaApi {
-    pub fn new(ha_configuration: HaConfiguration) -> anyhow::Result<Self> {
+    pub fn new(ha_configuration: HaConfiguration, commands: CommandBus) -> anyhow::Result<Self> {
+        let client = create_client(&ha_configuration)?;
+
         Ok(Self {
             ha_configuration,
+            client,
+            check_api_status: true,
             queried_attributes: false,
+            posted_states: Mutex::new(HashMap::new()),
+            commands,
+            websocket: None,
+            ha_started: None,
+            websocket_connection: None,
+            websocket_backoff: Backoff::default(),
+            websocket_retry_at: None,
         })
     }
 
-    // fetch state of all entities used in the application, remove attributes that we are handling here, and save the rest to the entity struct
-    async fn update_ha_entities_with_attributes(&mut self) -> anyhow::Result<()> {
-        if self.queried_attributes {
+    // the websocket is optional, the states are still posted through REST while it is down and
+    // it is tried again with its own backoff
+    fn ensure_websocket(&mut self) {
+        let is_connected = self
+            .websocket
+            .as_ref()
+            .is_some_and(|websocket| websocket.is_connected());
+        if !self.ha_configuration.websocket || is_connected {
+            return;
+        }
+
+        if let Some(connection) = self.websocket_connection.take() {
+            if !connection.is_finished() {
+                self.websocket_connection = Some(connection);
+                return;
+            }
+
+            match connection.now_or_never() {
+                Some(Ok(Ok((websocket, ha_started)))) => {
+                    info!("Connected to the Home Assistant websocket API");
+                    self.websocket_backoff.reset();
+                    self.ha_started = Some(ha_started);
+                    self.websocket = Some(websocket);
+                    return;
+                }
+                Some(Ok(Err(error))) => {
+                    let delay = self.websocket_backoff.next_delay();
+                    error!(
+                        "Unable to connect to the Home Assistant websocket API, retrying in {:?}: {}",
+                        delay, error
+                    );
+                    self.websocket_retry_at = Some(Instant::now() + delay);
+                }
+                _ => error!("The Home Assistant websocket connection stopped unexpectedly"),
+            }
+        }
+
+        if self
+            .websocket_retry_at
+            .is_some_and(|retry_at| Instant::now() < retry_at)
+        {
+            return;
+        }
+
+        self.websocket_retry_at = None;
+        self.websocket_connection = Some(task::spawn(connect_websocket(
+            self.ha_configuration.clone(),
+            self.commands.clone(),
+        )));
+    }
+
+    /// One event per transition of the event fields, automations can trigger on them instead of
+    /// comparing states. Nothing is fired when everything is sent again (ex: after connecting)
+    /// as those are not transitions.
+    async fn fire_transition_events(
+        &self,
+        snapshot: &TeamsSnapshot,
+        previous: Option<&TeamsSnapshot>,
+    ) -> anyhow::Result<()> {
+        let Some(previous) = previous else {
+            return Ok(());
+        };
+
+        let events = transition_events(&self.ha_configuration, snapshot, previous);
+        let fields: Vec<String> = events
+            .iter()
+            .map(|event_data| event_data["field"].to_string())
+            .collect();
+        let futures = events
+            .into_iter()
+            .map(|event_data| self.fire_event(event_data))
+            .collect();
+
+        // everything is sent again on the retry, which fires no events
+        send_limited(futures).await.inspect_err(|_| {
+            error!(
+                "The transitions of {} may not have reached Home Assistant, they are not fired again",
+                fields.join(", ")
+            )
+        })
+    }
+
+    async fn fire_event(&self, event_data: HashMap<String, Value>) -> anyhow::Result<()> {
+        info!(
+            "Firing HA event '{}' for {}",
+            self.ha_configuration.event_type, event_data["field"]
+        );
+
+        let params = EventParams {
+            event_type: self.ha_configuration.event_type.to_string(),
+            event_data,
+        };
+
+        if let Err(error) = self.client.post_events(params).await {
+            return Err(anyhow!(
+                "Unable to fire HA event ({}): {}",
+                self.ha_configuration.event_type,
+                error
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Whether HA lost the states we posted, which happens when it restarts as the entities
+    /// created through the API are not persisted. Only one entity is checked to keep it light.
+    async fn states_lost(&self) -> anyhow::Result<bool> {
+        let entity_id = &self.ha_configuration.entities.is_in_meeting.id;
+        let Some(posted_state) = self.posted_states.lock().unwrap().get(entity_id).cloned() else {
+            return Ok(false);
+        };
+
+        Ok(match self.client.get_states_of_entity(entity_id).await {
+            Ok(state) => state.state != posted_state,
+            // either HA is still starting, or it is up and the entity is gone
+            Err(_) => self.client.get_api_status().await.is_ok(),
+        })
+    }
+
+    async fn ensure_api_running(&mut self) -> anyhow::Result<()> {
+        if !self.check_api_status {
             return Ok(());
         }
 
-        let client = Client::new(
-            &self.ha_configuration.url,
-            &self.ha_configuration.long_live_token,
-        )?;
-        let api_status = client.get_api_status().await;
+        let api_status = self.client.get_api_status().await;
 
         if api_status.is_err() || api_status?.message != "API running." {
             error!("Home Assistant API cannot be reached");
             return Err(anyhow!("Home Assistant API cannot be reached"));
         }
 
+        self.check_api_status = false;
+        Ok(())
+    }
+
+    // fetch state of all entities used in the application, remove attributes that we are handling here, and save the rest to the entity struct
+    async fn update_ha_entities_with_attributes(&mut self) -> anyhow::Result<()> {
+        self.ensure_api_running().await?;
+        self.ensure_websocket();
+
+        if self.queried_attributes {
+            return Ok(());
+        }
+
         for (entity_id, entity) in self.ha_configuration.entities.iter_mut() {
-            let state = client.get_states_of_entity(&entity_id).await;
+            let state = self.client.get_states_of_entity(&entity_id).await;
 
             if state.is_err() {
                 error!("Error fetching entity state: {}", state.unwrap_err());
@@ -69,67 +239,79 @@ impl HaApi {
     // friendly_name is needed as API calls wipe the configured name
     async fn update_ha(
         &self,
-        state: &AtomicBool,
-        prev_state: &AtomicBool,
+        state: Option<bool>,
         ha_entity: &HaEntity,
-        force_update: bool,
+        dynamic_attributes: Option<&serde_json::Value>,
     ) -> anyhow::Result<()> {
-        let state_bool = state.load(Ordering::Relaxed);
-        let prev_state_bool = prev_state.load(Ordering::Relaxed);
-
-        // we exit early if nothing has changed, and we are not forcing an update
-        if state_bool == prev_state_bool && !force_update {
-            return Ok(());
-        }
-
-        let client = Client::new(
-            &self.ha_configuration.url,
-            &self.ha_configuration.long_live_token,
-        )?;
-        // use this code to connect and get attributes upon starting the application
-        let api_status = client.get_api_status().await;
+        let icon = if state == Some(true) {
+            &ha_entity.icons.on
+        } else {
+            &ha_entity.icons.off
+        };
 
-        if api_status.is_err() || api_status?.message != "API running." {
-            error!("Home Assistant API cannot be reached");
-            return Err(anyhow!("Home Assistant API cannot be reached"));
-        }
+        // HA shows 'unknown' when Teams did not tell us the state
+        self.post_state(ha_entity, &flag_to_str(state), icon, dynamic_attributes)
+            .await
+    }
 
+    async fn post_state(
+        &self,
+        ha_entity: &HaEntity,
+        state_str: &str,
+        icon: &str,
+        dynamic_attributes: Option<&serde_json::Value>,
+    ) -> anyhow::Result<()> {
         let mut attributes: HashMap<String, serde_json::Value> = HashMap::new();
         attributes.insert(
             "friendly_name".to_string(),
             json!(ha_entity.friendly_name.to_string()),
         );
 
-        let icon = if state_bool {
-            &ha_entity.icons.on
-        } else {
-            &ha_entity.icons.off
-        };
-
         attributes.insert("icon".to_string(), json!(icon.to_string()));
 
         for (key, value) in &ha_entity.additional_attributes {
             attributes.insert(key.to_string(), value.clone());
         }
 
-        let state_str = bool_to_str(state_bool);
+        if let Some(serde_json::Value::Object(dynamic_attributes)) = dynamic_attributes {
+            for (key, value) in dynamic_attributes {
+                attributes.insert(key.to_string(), value.clone());
+            }
+        }
+
         let params = StateParams {
             entity_id: ha_entity.id.to_string(),
-            state: state_str.clone(),
+            state: state_str.to_string(),
             attributes,
         };
 
-        info!("Updating HA entity ({}) to '{}'", &ha_entity.id, &state_str);
+        info!("Updating HA entity ({}) to '{}'", &ha_entity.id, state_str);
 
-        let post_states_res = client.post_states(params).await;
+        if let Err(error) = self.client.post_states(params).await {
+            return Err(anyhow!(
+                "Unable to update HA entity ({}): {}",
+                &ha_entity.id,
+                error
+            ));
+        }
 
-        if post_states_res.is_err() {
-            error!("{}", post_states_res.unwrap_err());
-        };
+        self.posted_states
+            .lock()
+            .unwrap()
+            .insert(ha_entity.id.to_string(), state_str.to_string());
+        Ok(())
+    }
 
-        prev_state.store(state_bool, Ordering::Relaxed);
+    // the friendly name and icon are kept, so the entities still look right in HA's dashboards
+    async fn post_unavailable(&self, entities: Vec<HaEntity>) -> anyhow::Result<()> {
+        let futures = entities
+            .iter()
+            .map(|ha_entity| {
+                self.post_state(ha_entity, HA_STATE_UNAVAILABLE, &ha_entity.icons.off, None)
+            })
+            .collect();
 
-        Ok(())
+        send_limited(futures).await
     }
 }
 
@@ -137,77 +319,230 @@ impl HaApi {
 impl Listener for HaApi {
     async fn notify_changed(
         &mut self,
-        teams_states: &TeamsStates,
-        force_update: bool,
+        snapshot: &TeamsSnapshot,
+        previous: Option<&TeamsSnapshot>,
     ) -> anyhow::Result<()> {
         self.update_ha_entities_with_attributes().await?;
 
-        // Reflection would be nice here... Tried with bevy_reflect but ran into an issue with AtomicBool
-        let mut futures = Vec::new();
-        // let is_in_meeting = self.ha_configuration.entities.is_in_meeting.clone();
-
-        futures.push(self.update_ha(
-            &teams_states.is_in_meeting,
-            &teams_states.prev_is_in_meeting,
-            &self.ha_configuration.entities.is_in_meeting,
-            force_update,
-        ));
-
-        futures.push(self.update_ha(
-            &teams_states.is_video_on,
-            &teams_states.prev_is_video_on,
-            &self.ha_configuration.entities.is_video_on,
-            force_update,
-        ));
-
-        futures.push(self.update_ha(
-            &teams_states.is_muted,
-            &teams_states.prev_is_muted,
-            &self.ha_configuration.entities.is_muted,
-            force_update,
-        ));
-
-        futures.push(self.update_ha(
-            &teams_states.is_hand_raised,
-            &teams_states.prev_is_hand_raised,
-            &self.ha_configuration.entities.is_hand_raised,
-            force_update,
-        ));
-
-        futures.push(self.update_ha(
-            &teams_states.is_recording_on,
-            &teams_states.prev_is_recording_on,
-            &self.ha_configuration.entities.is_recording_on,
-            force_update,
-        ));
-
-        futures.push(self.update_ha(
-            &teams_states.is_background_blurred,
-            &teams_states.prev_is_background_blurred,
-            &self.ha_configuration.entities.is_background_blurred,
-            force_update,
-        ));
-
-        futures.push(self.update_ha(
-            &teams_states.is_sharing,
-            &teams_states.prev_is_sharing,
-            &self.ha_configuration.entities.is_sharing,
-            force_update,
-        ));
-
-        futures.push(self.update_ha(
-            &teams_states.has_unread_messages,
-            &teams_states.prev_has_unread_messages,
-            &self.ha_configuration.entities.has_unread_messages,
-            force_update,
-        ));
-
-        try_join_all(futures).await?;
+        let changed_fields = snapshot.diff(previous);
+        // Meeting permissions are published as attributes of the meeting entity
+        let permissions_attributes = snapshot.permissions.as_ref().map(|p| p.to_json());
+        let entities = &self.ha_configuration.entities;
+
+        let futures = [
+            (TeamsField::InMeeting, &entities.is_in_meeting),
+            (TeamsField::VideoOn, &entities.is_video_on),
+            (TeamsField::Muted, &entities.is_muted),
+            (TeamsField::HandRaised, &entities.is_hand_raised),
+            (TeamsField::RecordingOn, &entities.is_recording_on),
+            (
+                TeamsField::BackgroundBlurred,
+                &entities.is_background_blurred,
+            ),
+            (TeamsField::Sharing, &entities.is_sharing),
+            (TeamsField::UnreadMessages, &entities.has_unread_messages),
+        ]
+        .into_iter()
+        .filter_map(|(field, ha_entity)| {
+            let is_in_meeting = field == TeamsField::InMeeting;
+            let has_changed = changed_fields.contains(&field)
+                || (is_in_meeting && changed_fields.contains(&TeamsField::Permissions));
+
+            has_changed.then(|| {
+                let dynamic_attributes = permissions_attributes.as_ref().filter(|_| is_in_meeting);
+                self.update_ha(snapshot.flag(field), ha_entity, dynamic_attributes)
+            })
+        })
+        .collect();
 
-        Ok(())
+        send_limited(futures).await?;
+        self.fire_transition_events(snapshot, previous).await
+    }
+
+    async fn notify_unavailable(&mut self) -> anyhow::Result<()> {
+        self.update_ha_entities_with_attributes().await?;
+
+        let entities = self.ha_configuration.entities.clone().into_iter();
+        self.post_unavailable(entities.map(|(_, ha_entity)| ha_entity).collect())
+            .await
+    }
+
+    async fn wait_for_resync(&mut self) {
+        loop {
+            if let Some(ha_started) = &mut self.ha_started {
+                match ha_started.recv().await {
+                    Some(_) => info!("Home Assistant started"),
+                    // HA is probably restarting, it is connected again with the states
+                    None => {
+                        info!("Home Assistant websocket closed");
+                        self.ha_started = None;
+                    }
+                }
+
+                return;
+            }
+
+            tokio::time::sleep(RESYNC_CHECK_INTERVAL).await;
+
+            // the websocket may have connected in the background meanwhile
+            self.ensure_websocket();
+            if self.ha_started.is_some() {
+                continue;
+            }
+
+            match self.states_lost().await {
+                Ok(true) => {
+                    info!("Home Assistant lost the states, it probably restarted");
+                    return;
+                }
+                Ok(false) => {}
+                Err(error) => error!("Unable to check the Home Assistant states: {}", error),
+            }
+        }
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        // HA was never reached, there is nothing to take back
+        if !self.queried_attributes {
+            return Ok(());
+        }
+
+        // when Teams was lost the entities are already unavailable
+        let posted_states = self.posted_states.lock().unwrap().clone();
+        let entities: Vec<HaEntity> = self
+            .ha_configuration
+            .entities
+            .clone()
+            .into_iter()
+            .filter(|(entity_id, _)| {
+                posted_states.get(entity_id).map(String::as_str) != Some(HA_STATE_UNAVAILABLE)
+            })
+            .map(|(_, ha_entity)| ha_entity)
+            .collect();
+
+        if entities.is_empty() {
+            return Ok(());
+        }
+
+        info!("Setting the Home Assistant entities to unavailable");
+        self.post_unavailable(entities).await
     }
 
     fn reconnect(&mut self) {
-        // considered not needed for now, as I believe the API will reconnect upon failure (not tested)
+        match create_client(&self.ha_configuration) {
+            Ok(client) => self.client = client,
+            Err(error) => error!("Unable to create the Home Assistant client: {}", error),
+        }
+
+        self.check_api_status = true;
+        self.websocket = None;
+        self.ha_started = None;
+        if let Some(connection) = self.websocket_connection.take() {
+            connection.abort();
+        }
+        self.websocket_backoff.reset();
+        self.websocket_retry_at = None;
+    }
+}
+
+async fn connect_websocket(
+    ha_configuration: HaConfiguration,
+    commands: CommandBus,
+) -> anyhow::Result<WebsocketConnection> {
+    let url = websocket_url(&ha_configuration.url)?;
+    let websocket = Arc::new(HaWebsocket::connect(&url, &ha_configuration.long_live_token).await?);
+    let ha_started = websocket.subscribe_events(HA_STARTED_EVENT).await?;
+    HaCommands::start(&websocket, commands, &ha_configuration).await?;
+
+    Ok((websocket, ha_started))
+}
+
+// the futures are collected beforehand, streaming them straight from the iterator does not
+// satisfy the Send bound of the listener
+async fn send_limited<F>(futures: Vec<F>) -> anyhow::Result<()>
+where
+    F: Future<Output = anyhow::Result<()>>,
+{
+    stream::iter(futures)
+        .buffer_unordered(MAX_CONCURRENT_REQUESTS)
+        .try_collect::<Vec<()>>()
+        .await?;
+
+    Ok(())
+}
+
+// ex: {"field": "is_muted", "from": false, "to": true, "changed_at": 1760786053, "hostname": "MyPC"},
+// `from` and `to` are null while Teams did not send the state
+fn transition_events(
+    ha_configuration: &HaConfiguration,
+    snapshot: &TeamsSnapshot,
+    previous: &TeamsSnapshot,
+) -> Vec<HashMap<String, Value>> {
+    snapshot
+        .diff(Some(previous))
+        .into_iter()
+        .filter(|field| ha_configuration.event_fields.contains(field))
+        .map(|field| {
+            let changed_at = snapshot
+                .changed_at(field)
+                .and_then(|changed_at| changed_at.duration_since(UNIX_EPOCH).ok())
+                .map(|changed_at| changed_at.as_secs());
+
+            HashMap::from([
+                ("field".to_string(), json!(field.key())),
+                ("from".to_string(), json!(previous.flag(field))),
+                ("to".to_string(), json!(snapshot.flag(field))),
+                ("changed_at".to_string(), json!(changed_at)),
+                ("hostname".to_string(), json!(computer_name())),
+            ])
+        })
+        .collect()
+}
+
+fn create_client(ha_configuration: &HaConfiguration) -> anyhow::Result<Client> {
+    Ok(Client::new(
+        &ha_configuration.url,
+        &ha_configuration.long_live_token,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::home_assistant::api::transition_events;
+    use crate::home_assistant::configuration::create_ha_configuration;
+    use crate::teams_ws::states::{TeamsField, TeamsSnapshot};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn transition_events_will_only_contain_event_fields() {
+        let mut ha_configuration = create_ha_configuration();
+        ha_configuration.event_fields = vec![TeamsField::Muted];
+        let mut previous = TeamsSnapshot::default();
+        previous.is_available = true;
+        previous.is_muted = Some(false);
+        let mut snapshot = previous.clone();
+        snapshot.is_muted = Some(true);
+        snapshot.is_recording_on = Some(true);
+
+        let events = transition_events(&ha_configuration, &snapshot, &previous);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["field"], "is_muted");
+        assert_eq!(events[0]["from"], json!(false));
+        assert_eq!(events[0]["to"], json!(true));
+    }
+
+    #[test]
+    fn transition_events_unknown_state_will_result_null() {
+        let ha_configuration = create_ha_configuration();
+        let previous = TeamsSnapshot::default();
+        let mut snapshot = previous.clone();
+        snapshot.is_sharing = Some(true);
+
+        let events = transition_events(&ha_configuration, &snapshot, &previous);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["field"], "is_sharing");
+        assert_eq!(events[0]["from"], Value::Null);
     }
 }
@@ -0,0 +1,198 @@
+use crate::home_assistant::configuration::HaConfiguration;
+use crate::home_assistant::websocket::HaWebsocket;
+use crate::teams_ws::commands::CommandBus;
+use futures_util::future;
+use log::{error, info};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use tokio::sync::mpsc;
+use tokio::task;
+
+// states an entity goes through when HA starts or loses it, nobody pressed anything
+const IGNORED_STATES: [&str; 2] = ["unavailable", "unknown"];
+
+/// Forwards the command events and the changes of the command entities to Teams, each outcome is
+/// fired back as an event. It stops once the websocket is closed or dropped.
+pub struct HaCommands {
+    commands: CommandBus,
+    websocket: Weak<HaWebsocket>,
+    result_event: String,
+    // entity id -> command
+    command_entities: HashMap<String, String>,
+}
+
+impl HaCommands {
+    pub async fn start(
+        websocket: &Arc<HaWebsocket>,
+        commands: CommandBus,
+        ha_configuration: &HaConfiguration,
+    ) -> anyhow::Result<()> {
+        let command_events = websocket
+            .subscribe_events(&ha_configuration.command_event)
+            .await?;
+        let command_entities: HashMap<String, String> = ha_configuration
+            .command_entities
+            .iter()
+            .map(|(command, entity_id)| (entity_id.to_string(), command.to_string()))
+            .collect();
+
+        let entity_ids: Vec<String> = command_entities.keys().cloned().collect();
+        let state_changes = if entity_ids.is_empty() {
+            None
+        } else {
+            Some(websocket.subscribe_state_changes(&entity_ids).await?)
+        };
+
+        let ha_commands = Self {
+            commands,
+            websocket: Arc::downgrade(websocket),
+            result_event: ha_configuration.command_result_event(),
+            command_entities,
+        };
+
+        task::spawn(ha_commands.run(command_events, state_changes));
+        Ok(())
+    }
+
+    async fn run(
+        self,
+        mut command_events: mpsc::UnboundedReceiver<Value>,
+        mut state_changes: Option<mpsc::UnboundedReceiver<Value>>,
+    ) {
+        loop {
+            let state_change = async {
+                match &mut state_changes {
+                    Some(state_changes) => state_changes.recv().await,
+                    None => future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                event = command_events.recv() => match event {
+                    Some(event) => self.handle_command_event(&event),
+                    None => break,
+                },
+                change = state_change => match change {
+                    Some(change) => self.handle_state_change(&change),
+                    None => break,
+                },
+            }
+        }
+    }
+
+    // ex: {"action": "send-reaction", "parameter": "like"}
+    fn handle_command_event(&self, event: &Value) {
+        let data = &event["data"];
+        let action = data["action"].as_str().unwrap_or_default();
+        let parameter = data["parameter"].as_str().unwrap_or_default();
+
+        info!("Command '{}' received from Home Assistant", action);
+        self.request(action, parameter);
+    }
+
+    fn handle_state_change(&self, event: &Value) {
+        let trigger = &event["variables"]["trigger"];
+        let Some(command) = trigger["entity_id"]
+            .as_str()
+            .and_then(|entity_id| self.command_entities.get(entity_id))
+        else {
+            return;
+        };
+
+        // the new state is the parameter, so an input_select can pick the reaction to send
+        if let Some(state) = changed_state(trigger) {
+            info!("Command '{}' requested by a Home Assistant entity", command);
+            self.request(command, state);
+        }
+    }
+
+    fn request(&self, action: &str, parameter: &str) {
+        let outcome = self.commands.request_action(action, parameter);
+        let websocket = self.websocket.clone();
+        let result_event = self.result_event.clone();
+        let action = action.to_string();
+        let parameter = parameter.to_string();
+
+        task::spawn(async move {
+            let outcome = outcome.await;
+
+            // the websocket may have been replaced meanwhile, the outcome is then only logged
+            let Some(websocket) = websocket.upgrade() else {
+                return;
+            };
+
+            let result = outcome.to_json(&action, &parameter);
+            if let Err(error) = websocket.fire_event(&result_event, result).await {
+                error!("Unable to fire the result of '{}': {}", action, error);
+            }
+        });
+    }
+}
+
+/// The state the entity changed to, None when the change does not come from a user (ex: the
+/// entity being restored when HA starts, or renamed which only changes its attributes)
+fn changed_state(trigger: &Value) -> Option<&str> {
+    let from_state = trigger["from_state"]["state"].as_str()?;
+    let to_state = trigger["to_state"]["state"].as_str()?;
+
+    if from_state == to_state
+        || IGNORED_STATES.contains(&from_state)
+        || IGNORED_STATES.contains(&to_state)
+    {
+        return None;
+    }
+
+    Some(to_state)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::home_assistant::commands::changed_state;
+    use serde_json::json;
+
+    #[test]
+    fn changed_state_button_pressed_will_result_new_state() {
+        let trigger = json!({
+            "entity_id": "input_button.teams_toggle_mute",
+            "from_state": { "state": "2026-10-18T09:12:03+00:00" },
+            "to_state": { "state": "2026-10-18T09:15:41+00:00" }
+        });
+
+        assert_eq!(changed_state(&trigger), Some("2026-10-18T09:15:41+00:00"));
+    }
+
+    #[test]
+    fn changed_state_entity_restored_will_be_ignored() {
+        let created = json!({
+            "entity_id": "input_button.teams_leave",
+            "from_state": null,
+            "to_state": { "state": "2026-10-18T09:15:41+00:00" }
+        });
+        let restored = json!({
+            "entity_id": "input_button.teams_leave",
+            "from_state": { "state": "unavailable" },
+            "to_state": { "state": "2026-10-18T09:15:41+00:00" }
+        });
+
+        assert_eq!(changed_state(&created), None);
+        assert_eq!(changed_state(&restored), None);
+    }
+
+    #[test]
+    fn changed_state_attributes_only_will_be_ignored() {
+        let trigger = json!({
+            "entity_id": "input_button.teams_leave",
+            "from_state": {
+                "state": "2026-10-18T09:15:41+00:00",
+                "attributes": { "friendly_name": "Leave" }
+            },
+            "to_state": {
+                "state": "2026-10-18T09:15:41+00:00",
+                "attributes": { "friendly_name": "Leave the Teams call" }
+            }
+        });
+
+        assert_eq!(changed_state(&trigger), None);
+    }
+}
@@ -1,8 +1,17 @@
-use std::collections::HashMap;
+use crate::teams_ws::states::TeamsField;
+use std::collections::{BTreeMap, HashMap};
 
 pub const HOME_ASSISTANT: &str = "Home Assistant";
 pub const HA_LONG_LIVE_TOKEN: &str = "Long Live Token";
 pub const HA_URL: &str = "URL";
+pub const HA_WEBSOCKET: &str = "Websocket";
+pub const HA_COMMAND_EVENT: &str = "Command Event";
+// followed by the command, ex: `Command Entity - toggle-mute`
+pub const HA_COMMAND_ENTITY: &str = "Command Entity - ";
+pub const HA_EVENT_TYPE: &str = "Event Type";
+pub const HA_EVENT_FIELDS: &str = "Event Fields";
+// written instead of an empty list, as empty values are not loaded
+pub const HA_EVENT_FIELDS_NONE: &str = "none";
 pub const HA_MUTED: &str = "Home Assistant Entity - Muted";
 pub const HA_VIDEO_ON: &str = "Home Assistant Entity - Video On";
 pub const HA_HAND_RAISED: &str = "Home Assistant Entity - Hand Raised";
@@ -87,12 +96,46 @@ impl HaEntities {
     }
 }
 
+#[derive(Clone)]
 pub struct HaConfiguration {
     pub long_live_token: String,
     pub url: String,
+    // keeps a connection to HA's websocket API, for events going both ways
+    pub websocket: bool,
+    // HA event fired to send a command to Teams, the outcome is fired as `<event>_result`
+    pub command_event: String,
+    // the command sent to Teams when each entity changes, ex: toggle-mute -> input_button.teams_toggle_mute
+    pub command_entities: BTreeMap<String, String>,
+    // HA event fired for each transition of the event fields
+    pub event_type: String,
+    pub event_fields: Vec<TeamsField>,
     pub entities: HaEntities,
 }
 
+impl HaConfiguration {
+    // only the states have transitions, Teams going away and the permissions are not events
+    pub fn set_event_fields(&mut self, value: &str) {
+        self.event_fields = value
+            .split(',')
+            .filter_map(TeamsField::parse)
+            .filter(|field| !matches!(field, TeamsField::Available | TeamsField::Permissions))
+            .collect();
+    }
+
+    pub fn event_fields_str(&self) -> String {
+        if self.event_fields.is_empty() {
+            return HA_EVENT_FIELDS_NONE.to_string();
+        }
+
+        let keys: Vec<&str> = self.event_fields.iter().map(|field| field.key()).collect();
+        keys.join(", ")
+    }
+
+    pub fn command_result_event(&self) -> String {
+        format!("{}_result", self.command_event)
+    }
+}
+
 pub fn create_ha_configuration() -> HaConfiguration {
     let ha_entities = HaEntities {
         is_muted: HaEntity {
@@ -179,6 +222,34 @@ pub fn create_ha_configuration() -> HaConfiguration {
     HaConfiguration {
         long_live_token: "".to_string(),
         url: "".to_string(),
+        websocket: false,
+        command_event: "teams_status_command".to_string(),
+        command_entities: BTreeMap::new(),
+        event_type: "teams_status_changed".to_string(),
+        event_fields: vec![
+            TeamsField::InMeeting,
+            TeamsField::Muted,
+            TeamsField::VideoOn,
+            TeamsField::HandRaised,
+            TeamsField::RecordingOn,
+            TeamsField::BackgroundBlurred,
+            TeamsField::Sharing,
+            TeamsField::UnreadMessages,
+        ],
         entities: ha_entities,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::home_assistant::configuration::create_ha_configuration;
+    use crate::teams_ws::states::TeamsField;
+
+    #[test]
+    fn set_event_fields_without_transitions_will_be_ignored() {
+        let mut ha_configuration = create_ha_configuration();
+        ha_configuration.set_event_fields("is_muted, is_available, permissions");
+
+        assert_eq!(ha_configuration.event_fields, vec![TeamsField::Muted]);
+    }
+}
@@ -0,0 +1,289 @@
+use anyhow::anyhow;
+use futures_util::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, JoinHandle};
+use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+use url::Url;
+
+// how long HA gets to answer a command, or the authentication
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Request {
+    Command {
+        message: Value,
+        responder: oneshot::Sender<anyhow::Result<Value>>,
+    },
+    Subscribe {
+        message: Value,
+        events: mpsc::UnboundedSender<Value>,
+        responder: oneshot::Sender<anyhow::Result<Value>>,
+    },
+}
+
+/// An authenticated connection to HA's websocket API. Commands get their own id and are answered
+/// through it, events of a subscription are forwarded to its receiver. Once HA closes the
+/// connection, commands fail and the event receivers end.
+pub struct HaWebsocket {
+    requests: mpsc::UnboundedSender<Request>,
+    connection: JoinHandle<()>,
+}
+
+impl HaWebsocket {
+    pub async fn connect(url: &Url, access_token: &str) -> anyhow::Result<Self> {
+        info!("Connecting to the Home Assistant websocket API: {}", url);
+        let (ws_stream, _) = tokio::time::timeout(RESPONSE_TIMEOUT, connect_async(url.as_str()))
+            .await
+            .map_err(|_| anyhow!("Home Assistant websocket API did not answer"))??;
+        let (mut write, mut read) = ws_stream.split();
+
+        // HA asks for the token first, nothing else is accepted until it is valid
+        let mut authenticated = false;
+        while !authenticated {
+            let message = tokio::time::timeout(RESPONSE_TIMEOUT, read.next())
+                .await
+                .map_err(|_| anyhow!("Home Assistant did not complete the authentication"))?
+                .ok_or_else(|| anyhow!("Home Assistant closed the websocket"))??;
+            let Message::Text(text) = message else {
+                continue;
+            };
+
+            let message: Value = serde_json::from_str(text.as_str())?;
+            match message["type"].as_str() {
+                Some("auth_required") => {
+                    let auth = json!({ "type": "auth", "access_token": access_token });
+                    write.send(Message::text(auth.to_string())).await?;
+                }
+                Some("auth_ok") => authenticated = true,
+                Some("auth_invalid") => {
+                    return Err(anyhow!(
+                        "Home Assistant rejected the token: {}",
+                        message["message"].as_str().unwrap_or_default()
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        info!("Connected to the Home Assistant websocket API");
+        let (requests, mut receiver) = mpsc::unbounded_channel::<Request>();
+        let connection = task::spawn(async move {
+            let mut next_id: u64 = 1;
+            let mut pending: HashMap<u64, oneshot::Sender<anyhow::Result<Value>>> = HashMap::new();
+            let mut subscriptions: HashMap<u64, mpsc::UnboundedSender<Value>> = HashMap::new();
+
+            loop {
+                tokio::select! {
+                    request = receiver.recv() => {
+                        let Some(request) = request else {
+                            break;
+                        };
+
+                        let (mut message, responder) = match request {
+                            Request::Command { message, responder } => (message, responder),
+                            Request::Subscribe { message, events, responder } => {
+                                subscriptions.insert(next_id, events);
+                                (message, responder)
+                            }
+                        };
+
+                        message["id"] = json!(next_id);
+                        if let Err(error) = write.send(Message::text(message.to_string())).await {
+                            let _ = responder.send(Err(error.into()));
+                            break;
+                        }
+
+                        pending.insert(next_id, responder);
+                        next_id += 1;
+                    },
+                    message = read.next() => {
+                        let text = match message {
+                            Some(Ok(Message::Text(text))) => text,
+                            Some(Ok(Message::Close(_))) | None => {
+                                warn!("Home Assistant closed the websocket");
+                                break;
+                            }
+                            Some(Ok(_)) => continue,
+                            Some(Err(error)) => {
+                                error!("Home Assistant websocket error: {}", error);
+                                break;
+                            }
+                        };
+
+                        match serde_json::from_str::<Value>(text.as_str()) {
+                            Ok(message) => dispatch(message, &mut pending, &subscriptions),
+                            Err(error) => error!("Unable to parse Home Assistant message: {}", error),
+                        }
+                    },
+                }
+            }
+        });
+
+        Ok(Self {
+            requests,
+            connection,
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        !self.connection.is_finished()
+    }
+
+    /// Sends a command (ex: `{"type": "get_config"}`) and waits for its result
+    pub async fn command(&self, message: Value) -> anyhow::Result<Value> {
+        let (responder, response) = oneshot::channel();
+        self.send(Request::Command { message, responder })?;
+        wait_for(response).await
+    }
+
+    pub async fn fire_event(&self, event_type: &str, event_data: Value) -> anyhow::Result<()> {
+        self.command(json!({
+            "type": "fire_event",
+            "event_type": event_type,
+            "event_data": event_data,
+        }))
+        .await?;
+
+        Ok(())
+    }
+
+    /// Every event of that type is sent to the receiver, until the connection is closed
+    pub async fn subscribe_events(
+        &self,
+        event_type: &str,
+    ) -> anyhow::Result<mpsc::UnboundedReceiver<Value>> {
+        self.subscribe(json!({ "type": "subscribe_events", "event_type": event_type }))
+            .await
+    }
+
+    /// Fires for every state change of those entities (`to: null` leaves out attribute only
+    /// changes), the change (`entity_id`, `from_state`, `to_state`) is under `variables.trigger`
+    pub async fn subscribe_state_changes(
+        &self,
+        entity_ids: &[String],
+    ) -> anyhow::Result<mpsc::UnboundedReceiver<Value>> {
+        self.subscribe(json!({
+            "type": "subscribe_trigger",
+            "trigger": { "platform": "state", "entity_id": entity_ids, "to": null },
+        }))
+        .await
+    }
+
+    async fn subscribe(&self, message: Value) -> anyhow::Result<mpsc::UnboundedReceiver<Value>> {
+        let (events, receiver) = mpsc::unbounded_channel();
+        let (responder, response) = oneshot::channel();
+        self.send(Request::Subscribe {
+            message,
+            events,
+            responder,
+        })?;
+        wait_for(response).await?;
+
+        Ok(receiver)
+    }
+
+    fn send(&self, request: Request) -> anyhow::Result<()> {
+        self.requests
+            .send(request)
+            .map_err(|_| anyhow!("The Home Assistant websocket is closed"))
+    }
+}
+
+impl Drop for HaWebsocket {
+    fn drop(&mut self) {
+        self.connection.abort();
+    }
+}
+
+async fn wait_for(response: oneshot::Receiver<anyhow::Result<Value>>) -> anyhow::Result<Value> {
+    tokio::time::timeout(RESPONSE_TIMEOUT, response)
+        .await
+        .map_err(|_| anyhow!("Home Assistant did not answer in time"))?
+        .map_err(|_| anyhow!("The Home Assistant websocket is closed"))?
+}
+
+fn dispatch(
+    message: Value,
+    pending: &mut HashMap<u64, oneshot::Sender<anyhow::Result<Value>>>,
+    subscriptions: &HashMap<u64, mpsc::UnboundedSender<Value>>,
+) {
+    let Some(id) = message["id"].as_u64() else {
+        return;
+    };
+
+    match message["type"].as_str() {
+        Some("result") => {
+            if let Some(responder) = pending.remove(&id) {
+                let _ = responder.send(command_result(message));
+            }
+        }
+        Some("event") => {
+            if let Some(events) = subscriptions.get(&id) {
+                let _ = events.send(message["event"].clone());
+            }
+        }
+        _ => {}
+    }
+}
+
+fn command_result(mut message: Value) -> anyhow::Result<Value> {
+    if message["success"].as_bool() == Some(true) {
+        Ok(message["result"].take())
+    } else {
+        Err(anyhow!(
+            "Home Assistant refused the command: {}",
+            message["error"]["message"]
+                .as_str()
+                .unwrap_or("unknown error")
+        ))
+    }
+}
+
+/// HA's websocket endpoint, next to the REST API (behind the same base path for reverse proxies)
+pub fn websocket_url(ha_url: &str) -> anyhow::Result<Url> {
+    let mut url = Url::parse(ha_url)?;
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+
+    url.set_scheme(scheme)
+        .map_err(|_| anyhow!("Invalid Home Assistant URL: {}", ha_url))?;
+    let path = format!("{}/api/websocket", url.path().trim_end_matches('/'));
+    url.set_path(&path);
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::home_assistant::websocket::{command_result, websocket_url};
+    use serde_json::json;
+
+    #[test]
+    fn websocket_url_will_follow_scheme() {
+        assert_eq!(
+            websocket_url("http://192.168.1.20:8123").unwrap().as_str(),
+            "ws://192.168.1.20:8123/api/websocket"
+        );
+        assert_eq!(
+            websocket_url("https://ha.example.com/").unwrap().as_str(),
+            "wss://ha.example.com/api/websocket"
+        );
+        assert_eq!(
+            websocket_url("https://example.com/ha/").unwrap().as_str(),
+            "wss://example.com/ha/api/websocket"
+        );
+    }
+
+    #[test]
+    fn command_result_failure_will_include_message() {
+        let result = command_result(json!({
+            "id": 3,
+            "type": "result",
+            "success": false,
+            "error": { "code": "unknown_command", "message": "Unknown command." }
+        }));
+
+        assert!(result.unwrap_err().to_string().contains("Unknown command."));
+    }
+}
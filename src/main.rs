@@ -1,6 +1,7 @@
 #![windows_subsystem = "windows"]
 
 mod configuration;
+mod connection;
 mod home_assistant;
 mod logging;
 mod mqtt;
@@ -12,86 +13,132 @@ mod utils;
 
 use mutex::{create_mutex, release_mutex};
 use std::process::exit;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
 use tray_icon::{menu::MenuEvent, TrayIconEvent};
 use winit::application::ApplicationHandler;
 use winit::event::WindowEvent;
-use winit::event_loop::{ActiveEventLoop, EventLoop};
+use winit::event_loop::{ActiveEventLoop, EventLoop, EventLoopProxy};
 use winit::window::WindowId;
 
-use crate::configuration::get_configuration;
+use crate::configuration::{get_configuration, Configuration};
+use crate::connection::{
+    Backoff, ConnectionState, ConnectionStatus, ListenerConnection, SHUTDOWN_TIMEOUT,
+};
+use crate::home_assistant::configuration::HOME_ASSISTANT;
 use crate::logging::initialize_logging;
 use crate::mqtt::api::MqttApi;
+use crate::mqtt::configuration::MQTT;
 use crate::teams_ws::api::TeamsAPI;
+use crate::teams_ws::commands::{CommandBus, CommandOutcome, CommandReceiver, TeamsCommand};
+use crate::teams_ws::configuration::TEAMS;
+use crate::teams_ws::pairing::{Pairing, PairingState};
 use crate::traits::Listener;
 use crate::tray::create_tray;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use home_assistant::api::HaApi;
-use log::{error, info};
+use log::{error, info, warn};
 
 #[derive(Debug)]
 enum UserEvent {
     TrayIconEvent(TrayIconEvent),
     MenuEvent(MenuEvent),
+    CommandOutcome(TeamsCommand, CommandOutcome),
 }
 
 struct Application {
-    is_running: Arc<AtomicBool>,
-    toggle_mute: Arc<AtomicBool>,
+    commands: CommandBus,
+    // the answers of the commands sent from the tray come back as user events
+    proxy: EventLoopProxy<UserEvent>,
+    runtime: Handle,
+    connection_status: ConnectionStatus,
+    pairing: Pairing,
     _tray: Option<Box<dyn traits::StopController>>,
 }
 
 impl Application {
-    fn new(is_running: Arc<AtomicBool>, toggle_mute: Arc<AtomicBool>) -> Self {
+    fn new(
+        commands: CommandBus,
+        proxy: EventLoopProxy<UserEvent>,
+        runtime: Handle,
+        connection_status: ConnectionStatus,
+        pairing: Pairing,
+    ) -> Self {
         Self {
-            is_running,
-            toggle_mute,
+            commands,
+            proxy,
+            runtime,
+            connection_status,
+            pairing,
             _tray: None,
         }
     }
+
+    fn tray_windows(&mut self) -> Option<&mut crate::tray::TrayWindows> {
+        self._tray
+            .as_mut()?
+            .as_any_mut()
+            .downcast_mut::<crate::tray::TrayWindows>()
+    }
+
+    fn request(&self, command: TeamsCommand) {
+        let outcome = self.commands.request(command);
+        let proxy = self.proxy.clone();
+
+        self.runtime.spawn(async move {
+            // the application is closing when there is no outcome, nothing to show then
+            if let Ok(outcome) = outcome.await {
+                let _ = proxy.send_event(UserEvent::CommandOutcome(command, outcome));
+            }
+        });
+    }
 }
 
 impl ApplicationHandler<UserEvent> for Application {
-    fn new_events(&mut self, event_loop: &ActiveEventLoop, cause: winit::event::StartCause) {
+    fn new_events(&mut self, _event_loop: &ActiveEventLoop, cause: winit::event::StartCause) {
         if matches!(cause, winit::event::StartCause::Init) {
             // Create tray icon when event loop starts
-            self._tray = Some(create_tray(
-                self.is_running.clone(),
-                self.toggle_mute.clone(),
-            ));
+            self._tray = Some(create_tray());
         }
 
-        // Check if tray needs recreation (for menu label updates)
-        if let Some(tray) = &mut self._tray {
-            if let Some(tray_windows) = tray.as_any_mut().downcast_mut::<crate::tray::TrayWindows>()
-            {
-                tray_windows.check_and_recreate_if_needed();
-            }
+        // the pairing progress comes first, the summary may be cut in the tooltip
+        let pairing_state = self.pairing.state();
+        let mut status = String::new();
+        if pairing_state != PairingState::Paired {
+            status.push_str(&format!("Teams pairing: {}\n", pairing_state));
         }
+        status.push_str(&self.connection_status.summary());
 
-        // Check if application should exit
-        if !self.is_running.load(Ordering::Relaxed) {
-            event_loop.exit();
+        if let Some(tray_windows) = self.tray_windows() {
+            // Check if tray needs recreation (for menu label updates)
+            tray_windows.check_and_recreate_if_needed();
+            tray_windows.show_connection_status(&status);
         }
     }
 
     fn resumed(&mut self, _event_loop: &ActiveEventLoop) {}
 
-    fn user_event(&mut self, _event_loop: &ActiveEventLoop, event: UserEvent) {
+    fn user_event(&mut self, event_loop: &ActiveEventLoop, event: UserEvent) {
         match event {
             UserEvent::MenuEvent(event) => {
-                if event.id.0.as_str() == "toggle_mute" {
-                    self.toggle_mute.store(true, Ordering::Relaxed);
+                if let Some(command) = tray::command_from_menu_id(event.id.0.as_str()) {
+                    self.request(command);
+                } else if event.id.0.as_str() == "pair_teams" {
+                    self.pairing.request();
                 } else if event.id.0.as_str() == "quit" {
-                    self.is_running.store(false, Ordering::Relaxed);
+                    self.commands.shutdown();
+                    event_loop.exit();
                 } else if event.id.0.as_str() == "launch_startup" {
                     tray::TrayWindows::toggle_auto_launch_global();
                 }
             }
             UserEvent::TrayIconEvent(_event) => {}
+            // shown as soon as Teams replied, without waiting for the next tray event
+            UserEvent::CommandOutcome(command, outcome) => {
+                if let Some(tray_windows) = self.tray_windows() {
+                    tray_windows.show_command_outcome(command, &outcome);
+                }
+            }
         }
     }
 
@@ -115,8 +162,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         exit(1)
     }
 
-    let toggle_mute = Arc::new(AtomicBool::new(false));
-    let is_running = Arc::new(AtomicBool::new(true));
+    let (commands, command_receiver) = CommandBus::new();
+    let pairing = Pairing::default();
+    if std::env::args().skip(1).any(|arg| arg == "--pair") {
+        pairing.request();
+    }
 
     // Create winit event loop
     let event_loop = EventLoop::<UserEvent>::with_user_event().build()?;
@@ -132,43 +182,38 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let _ = proxy.send_event(UserEvent::MenuEvent(event));
     }));
 
-    let mut app = Application::new(is_running.clone(), toggle_mute.clone());
-
+    let connection_status = ConnectionStatus::default();
     // Spawn async tasks in background - don't capture mutex
     let rt = tokio::runtime::Runtime::new()?;
-    let is_running_clone = is_running.clone();
-    let toggle_mute_clone = toggle_mute.clone();
+    let mut app = Application::new(
+        commands.clone(),
+        event_loop.create_proxy(),
+        rt.handle().clone(),
+        connection_status.clone(),
+        pairing.clone(),
+    );
+
+    let (closed_sender, closed_receiver) = std::sync::mpsc::channel();
 
     std::thread::spawn(move || {
         rt.block_on(async {
-            let five_seconds = time::Duration::from_secs(5);
-            let mut save_configuration = true;
-
-            while is_running_clone.load(Ordering::Relaxed) {
-                let result = run_apis(
-                    is_running_clone.clone(),
-                    toggle_mute_clone.clone(),
-                    save_configuration,
-                )
-                .await;
-                save_configuration = false;
-
-                if result.is_err() {
-                    result.unwrap_or_else(|error| error!("Error encountered: {}", error));
-                    if is_running_clone.load(Ordering::Relaxed) {
-                        tokio::time::sleep(five_seconds).await;
-                    }
-                }
-            }
+            run_apis(commands, command_receiver, connection_status, pairing)
+                .await
+                .unwrap_or_else(|error| error!("Error encountered: {}", error));
 
             info!("Application closing");
             // Don't access mutex here - it will be cleaned up in main thread
         });
+        let _ = closed_sender.send(());
     });
 
     // Run the event loop - this is required for tray menu to work
     event_loop.run_app(&mut app)?;
 
+    if closed_receiver.recv_timeout(SHUTDOWN_TIMEOUT).is_err() {
+        warn!("Connections did not close in time");
+    }
+
     // Clean up mutex after event loop exits
     info!("Application closing");
     release_mutex(mutex);
@@ -176,25 +221,128 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 async fn run_apis(
-    is_running: Arc<AtomicBool>,
-    toggle_mute: Arc<AtomicBool>,
-    save_configuration: bool,
+    command_bus: CommandBus,
+    mut commands: CommandReceiver,
+    connection_status: ConnectionStatus,
+    pairing: Pairing,
 ) -> Result<()> {
-    let conf = get_configuration(save_configuration);
-    let teams_api = TeamsAPI::new(&conf.teams);
-    let listener: Box<dyn Listener> = if conf.mqtt.url().is_empty() {
-        Box::new(HaApi::new(conf.ha)?)
-    } else {
-        Box::new(MqttApi::new(conf.mqtt)?)
-    };
-
-    teams_api
-        .start_listening(
-            Arc::new(Mutex::new(listener)),
-            is_running.clone(),
-            toggle_mute.clone(),
-        )
-        .await?;
+    let conf = get_configuration(true);
+    let teams_api = TeamsAPI::new(&conf.teams, connection_status.clone(), pairing);
+    let listeners = create_listeners(conf, &command_bus, &connection_status);
+    if listeners.is_empty() {
+        warn!("No Home Assistant or MQTT URL is configured, Teams states are not published");
+    }
+
+    // every listener runs on its own, a slow or failing one never holds Teams or the others back
+    let listener_tasks: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| tokio::spawn(listener.run(teams_api.subscribe())))
+        .collect();
+    let mut backoff = Backoff::default();
+
+    while !commands.is_shutdown() {
+        let connected_at = Instant::now();
+        let result = teams_api.start_listening(&mut commands).await;
+        let is_stable = connected_at.elapsed() >= backoff.max();
+        // a connection that stayed up for a while starts the delays over, one Teams keeps
+        // dropping is retried with the increasing delay like any other failure
+        if is_stable {
+            backoff.reset();
+        }
+        let result = result.and_then(|()| {
+            if is_stable || commands.is_shutdown() {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "Teams closed the connection after {:?}",
+                    connected_at.elapsed()
+                ))
+            }
+        });
+
+        match result {
+            // we were connected for a while, so we can try again right away
+            Ok(()) => {}
+            Err(error) => {
+                let delay = backoff.next_delay();
+                error!("Unable to reach Teams, retrying in {:?}: {}", delay, error);
+                let until = Instant::now() + delay;
+                connection_status.set(
+                    TEAMS,
+                    ConnectionState::BackingOff {
+                        until,
+                        error: error.to_string(),
+                    },
+                );
+
+                while !commands.is_shutdown() && Instant::now() < until {
+                    let remaining = until.saturating_duration_since(Instant::now());
+                    tokio::select! {
+                        request = commands.recv() => if let Some(request) = request {
+                            warn!(
+                                "Teams is not connected, dropping '{}'",
+                                request.command.action()
+                            );
+                            request.respond(CommandOutcome::Failure(
+                                "Teams is not connected".to_string(),
+                            ));
+                        },
+                        _ = tokio::time::sleep(remaining.min(Duration::from_secs(1))) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    // the listeners see Teams going away and stop once they handled it
+    drop(teams_api);
+    for listener_task in listener_tasks {
+        if let Err(error) = listener_task.await {
+            error!("A listener stopped unexpectedly: {}", error);
+        }
+    }
 
     Ok(())
 }
+
+fn create_listeners(
+    conf: Configuration,
+    commands: &CommandBus,
+    connection_status: &ConnectionStatus,
+) -> Vec<ListenerConnection> {
+    let ha_instances = std::iter::once((HOME_ASSISTANT.to_string(), conf.ha))
+        .chain(conf.additional_ha)
+        .filter(|(_, ha)| !ha.url.is_empty());
+    let mqtt_instances = std::iter::once((MQTT.to_string(), conf.mqtt))
+        .chain(conf.additional_mqtt)
+        .filter(|(_, mqtt)| !mqtt.url().is_empty());
+
+    let ha_listeners = ha_instances.map(|(name, ha)| {
+        let listener =
+            HaApi::new(ha, commands.clone()).map(|api| Box::new(api) as Box<dyn Listener>);
+        (name, listener)
+    });
+    let mqtt_listeners = mqtt_instances.map(|(name, mqtt)| {
+        let listener = MqttApi::new(&name, mqtt, commands.clone(), connection_status.clone())
+            .map(|api| Box::new(api) as Box<dyn Listener>);
+        (name, listener)
+    });
+
+    ha_listeners
+        .chain(mqtt_listeners)
+        .filter_map(|(name, listener)| match listener {
+            Ok(listener) => Some(ListenerConnection::new(
+                &name,
+                listener,
+                connection_status.clone(),
+            )),
+            // nothing will change until the configuration is fixed, so there is no retrying
+            // here, the other listeners carry on
+            Err(error) => {
+                error!("Unable to create listener '{}': {}", name, error);
+                connection_status.set(&name, ConnectionState::Failed(error.to_string()));
+                None
+            }
+        })
+        .collect()
+}
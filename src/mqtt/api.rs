@@ -1,98 +1,465 @@
+use crate::connection::{Backoff, ConnectionState, ConnectionStatus};
+use crate::mqtt::commands::MqttCommands;
 use crate::mqtt::configuration::MqttConfiguration;
-use crate::teams_ws::states::TeamsStates;
+use crate::mqtt::discovery::discovery_messages;
+use crate::mqtt::transport::create_transport;
+use crate::teams_ws::commands::CommandBus;
+use crate::teams_ws::states::{TeamsField, TeamsSnapshot};
 use crate::traits::Listener;
-use crate::utils::bool_to_str;
 use async_trait::async_trait;
-use rumqttc::{AsyncClient, MqttOptions, QoS};
-use serde_json::json;
-use std::sync::atomic::Ordering;
-use std::time::Duration;
-use tokio::task;
+use log::{error, info};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::{self, JoinHandle};
+
+const AVAILABILITY_ONLINE: &str = "online";
+const AVAILABILITY_OFFLINE: &str = "offline";
+const HA_STATUS_ONLINE: &str = "online";
+
+/// The connection to the broker as seen by the event loop, along with the latest retained
+/// message of every topic so that the broker is brought up to date whenever it comes back
+#[derive(Default)]
+struct BrokerState {
+    connected: bool,
+    retained: BTreeMap<String, String>,
+}
+
+#[derive(Clone)]
+struct Broker {
+    // shown in the connection status, next to the listener's own state
+    name: String,
+    status: ConnectionStatus,
+    state: Arc<Mutex<BrokerState>>,
+    // held from reading a retained message until it is queued, so that a republish can never
+    // queue an older value after a newer one
+    publishing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Broker {
+    fn connected(&self) {
+        self.state.lock().unwrap().connected = true;
+        self.status.set(&self.name, ConnectionState::Connected);
+    }
+
+    fn retained_topics(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .retained
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    fn retained_payload(&self, topic: &str) -> Option<String> {
+        self.state.lock().unwrap().retained.get(topic).cloned()
+    }
+
+    fn disconnected(&self, until: Instant, error: String) {
+        self.state.lock().unwrap().connected = false;
+        self.status
+            .set(&self.name, ConnectionState::BackingOff { until, error });
+    }
+
+    fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connected
+    }
+
+    /// Keeps the message for the next connection, returns whether it can be published now
+    fn retain(&self, topic: &str, payload: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state
+            .retained
+            .insert(topic.to_string(), payload.to_string());
+        state.connected
+    }
+
+    /// Publishes now if the broker is connected, otherwise once it is back
+    async fn publish_retained(
+        &self,
+        client: &AsyncClient,
+        topic: &str,
+        payload: &str,
+    ) -> anyhow::Result<()> {
+        let _publishing = self.publishing.lock().await;
+        if self.retain(topic, payload) {
+            client
+                .publish(topic, QoS::AtLeastOnce, true, payload)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
 
 pub struct MqttApi {
     client: AsyncClient,
+    event_loop: JoinHandle<()>,
+    // kept to reconnect with the same client id and certificates
+    mqtt_options: MqttOptions,
     mqtt_configuration: MqttConfiguration,
+    commands: CommandBus,
+    broker: Broker,
+    // discovery messages are kept with the other retained messages, so they are only sent once
+    published_discovery: bool,
 }
 
 impl MqttApi {
-    pub fn new(mqtt_configuration: MqttConfiguration) -> anyhow::Result<Self> {
-        let mut mqtt_options = MqttOptions::new(
-            "teams-status",
-            mqtt_configuration.url(),
-            mqtt_configuration.port,
+    pub fn new(
+        name: &str,
+        mqtt_configuration: MqttConfiguration,
+        commands: CommandBus,
+        status: ConnectionStatus,
+    ) -> anyhow::Result<Self> {
+        let mqtt_options = create_mqtt_options(&mqtt_configuration)?;
+        let broker = Broker {
+            name: format!("{} broker", name),
+            status,
+            state: Arc::default(),
+            publishing: Arc::default(),
+        };
+        broker.status.set(&broker.name, ConnectionState::Connecting);
+        let (client, event_loop) = connect(
+            mqtt_options.clone(),
+            &mqtt_configuration,
+            &commands,
+            broker.clone(),
         );
 
-        mqtt_options.set_credentials(&mqtt_configuration.username, &mqtt_configuration.password);
-        mqtt_options.set_keep_alive(Duration::from_secs(5));
-        let (client, mut event_loop) = AsyncClient::new(mqtt_options, 10);
-
-        // mqttc requires this to work
-        task::spawn(async move { while let Ok(_) = event_loop.poll().await {} });
-
         Ok(Self {
             client,
+            event_loop,
+            mqtt_options,
             mqtt_configuration,
+            commands,
+            broker,
+            published_discovery: false,
         })
     }
-}
 
-#[async_trait]
-impl Listener for MqttApi {
-    async fn notify_changed(&mut self, teams_states: &TeamsStates, _: bool) -> anyhow::Result<()> {
-        let muted = &*bool_to_str(teams_states.is_muted.load(Ordering::Relaxed));
-        let video_on = &*bool_to_str(teams_states.is_video_on.load(Ordering::Relaxed));
-        let hand_raised = &*bool_to_str(teams_states.is_hand_raised.load(Ordering::Relaxed));
-        let in_meeting = &*bool_to_str(teams_states.is_in_meeting.load(Ordering::Relaxed));
-        let recording = &*bool_to_str(teams_states.is_recording_on.load(Ordering::Relaxed));
-        let background_blurred =
-            &*bool_to_str(teams_states.is_background_blurred.load(Ordering::Relaxed));
-        let sharing = &*bool_to_str(teams_states.is_sharing.load(Ordering::Relaxed));
-        let unread_messages =
-            &*bool_to_str(teams_states.has_unread_messages.load(Ordering::Relaxed));
-
-        let mqtt_entities = &self.mqtt_configuration.mqtt_entities;
-
-        let payload = json!({
-            &mqtt_entities.muted:muted,
-            &mqtt_entities.video:video_on,
-            &mqtt_entities.hand_raised:hand_raised,
-            &mqtt_entities.meeting:in_meeting,
-            &mqtt_entities.recording:recording,
-            &mqtt_entities.background_blurred:background_blurred,
-            &mqtt_entities.sharing:sharing,
-            &mqtt_entities.unread_messages:unread_messages,
-        });
-
-        // todo: log failures
-        let _ = &self
-            .client
+    async fn publish_discovery(&mut self) -> anyhow::Result<()> {
+        if !self.mqtt_configuration.discovery || self.published_discovery {
+            return Ok(());
+        }
+
+        info!("Publishing Home Assistant MQTT discovery messages");
+        for (topic, payload) in discovery_messages(&self.mqtt_configuration) {
+            self.publish_retained(&topic, &payload).await?;
+        }
+
+        self.published_discovery = true;
+        Ok(())
+    }
+
+    async fn publish_retained(&self, topic: &str, payload: &str) -> anyhow::Result<()> {
+        self.broker
+            .publish_retained(&self.client, topic, payload)
+            .await
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        // the last will tells the broker as much when we never got to connect
+        if !self.broker.is_connected() {
+            return Ok(());
+        }
+
+        self.publish_teams_availability(AVAILABILITY_OFFLINE)
+            .await?;
+        self.client
             .publish(
-                &self.mqtt_configuration.topic,
+                self.mqtt_configuration.availability_topic(),
                 QoS::AtLeastOnce,
                 true,
-                payload.to_string(),
+                AVAILABILITY_OFFLINE,
             )
             .await?;
+        self.client.disconnect().await?;
 
+        // the messages are only queued so far, the event loop sends them before disconnecting
+        (&mut self.event_loop).await?;
         Ok(())
     }
 
+    async fn publish_teams_availability(&self, availability: &str) -> anyhow::Result<()> {
+        self.publish_retained(
+            &self.mqtt_configuration.teams_availability_topic(),
+            availability,
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl Listener for MqttApi {
+    async fn notify_changed(
+        &mut self,
+        snapshot: &TeamsSnapshot,
+        previous: Option<&TeamsSnapshot>,
+    ) -> anyhow::Result<()> {
+        self.publish_discovery().await?;
+
+        // the first update after connecting to Teams sends everything
+        if previous.is_none() {
+            self.publish_teams_availability(AVAILABILITY_ONLINE).await?;
+        }
+
+        if self.mqtt_configuration.publish_mode.publishes_topics() {
+            for (topic, payload) in state_messages(&self.mqtt_configuration, snapshot, previous) {
+                self.publish_retained(&topic, &payload).await?;
+            }
+        }
+
+        if self.mqtt_configuration.publish_mode.publishes_json() {
+            let payload = json_payload(&self.mqtt_configuration, snapshot).to_string();
+            self.publish_retained(&self.mqtt_configuration.topic, &payload)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn notify_unavailable(&mut self) -> anyhow::Result<()> {
+        self.publish_discovery().await?;
+        self.publish_teams_availability(AVAILABILITY_OFFLINE).await
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        info!("Publishing MQTT availability offline");
+        self.close().await
+    }
+
     fn reconnect(&mut self) {
-        let mut mqtt_options = MqttOptions::new(
-            "teams-status",
-            self.mqtt_configuration.url(),
-            self.mqtt_configuration.port,
+        self.event_loop.abort();
+        (self.client, self.event_loop) = connect(
+            self.mqtt_options.clone(),
+            &self.mqtt_configuration,
+            &self.commands,
+            self.broker.clone(),
         );
+    }
+}
+
+fn create_mqtt_options(mqtt_configuration: &MqttConfiguration) -> anyhow::Result<MqttOptions> {
+    let client_id = mqtt_configuration.client_id();
+    info!("Connecting to MQTT as '{}'", client_id);
+    let mut mqtt_options = MqttOptions::new(
+        client_id,
+        mqtt_configuration.broker_address(),
+        mqtt_configuration.port,
+    );
+
+    mqtt_options.set_transport(create_transport(mqtt_configuration)?);
+    mqtt_options.set_credentials(&mqtt_configuration.username, &mqtt_configuration.password);
+    mqtt_options.set_keep_alive(Duration::from_secs(5));
+    // the broker publishes it if the application stops without closing the connection
+    mqtt_options.set_last_will(LastWill::new(
+        mqtt_configuration.availability_topic(),
+        AVAILABILITY_OFFLINE,
+        QoS::AtLeastOnce,
+        true,
+    ));
+
+    Ok(mqtt_options)
+}
+
+fn connect(
+    mqtt_options: MqttOptions,
+    mqtt_configuration: &MqttConfiguration,
+    commands: &CommandBus,
+    broker: Broker,
+) -> (AsyncClient, JoinHandle<()>) {
+    let availability_topic = mqtt_configuration.availability_topic();
+    let (client, mut event_loop) = AsyncClient::new(mqtt_options, 10);
+    let broker_client = client.clone();
+    let mqtt_commands = MqttCommands::new(client.clone(), commands.clone(), mqtt_configuration);
+    // HA publishes `online` there when it starts, the discovery messages and states are then
+    // sent again in case they were not kept by the broker
+    let ha_status_topic = mqtt_configuration
+        .discovery
+        .then(|| mqtt_configuration.ha_status_topic());
+
+    // mqttc requires this to work, it is also where the commands come in. Polling again after
+    // an error reconnects, so the loop only ends when we disconnect
+    let event_loop = task::spawn(async move {
+        let mut backoff = Backoff::default();
+
+        loop {
+            let event = match event_loop.poll().await {
+                Ok(event) => event,
+                Err(error) => {
+                    let delay = backoff.next_delay();
+                    error!(
+                        "Unable to reach MQTT broker '{}', retrying in {:?}: {}",
+                        broker.name, delay, error
+                    );
+                    broker.disconnected(Instant::now() + delay, error.to_string());
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            };
+
+            // the subscriptions are queued before the republish can fill the request channel
+            mqtt_commands.handle_event(&event);
+
+            match &event {
+                Event::Incoming(Packet::ConnAck(_)) => {
+                    backoff.reset();
+                    broker.connected();
+
+                    if let Some(topic) = &ha_status_topic {
+                        if let Err(error) = broker_client.try_subscribe(topic, QoS::AtLeastOnce) {
+                            error!("Unable to subscribe to '{}': {}", topic, error);
+                        }
+                    }
+
+                    let birth = (availability_topic.clone(), AVAILABILITY_ONLINE.to_string());
+                    task::spawn(republish(
+                        broker_client.clone(),
+                        broker.clone(),
+                        Some(birth),
+                    ));
+                }
+                Event::Incoming(Packet::Publish(publish))
+                    if ha_status_topic.as_ref() == Some(&publish.topic)
+                        && publish.payload.as_ref() == HA_STATUS_ONLINE.as_bytes() =>
+                {
+                    info!("Home Assistant started, publishing the states again");
+                    task::spawn(republish(broker_client.clone(), broker.clone(), None));
+                }
+                Event::Outgoing(Outgoing::Disconnect) => break,
+                _ => {}
+            }
+        }
+    });
+
+    (client, event_loop)
+}
 
-        mqtt_options.set_credentials(
-            &self.mqtt_configuration.username,
-            &self.mqtt_configuration.password,
+/// Brings the broker up to date with the latest retained messages, the states may have changed
+/// while it was unreachable. Each one is read when it is published, as the listener may publish
+/// a newer value meanwhile.
+async fn republish(client: AsyncClient, broker: Broker, birth: Option<(String, String)>) {
+    if let Some((topic, payload)) = birth {
+        if let Err(error) = client
+            .publish(&topic, QoS::AtLeastOnce, true, payload)
+            .await
+        {
+            error!("Unable to publish to '{}': {}", topic, error);
+            return;
+        }
+    }
+
+    for topic in broker.retained_topics() {
+        let _publishing = broker.publishing.lock().await;
+        let Some(payload) = broker.retained_payload(&topic) else {
+            continue;
+        };
+
+        if let Err(error) = client
+            .publish(&topic, QoS::AtLeastOnce, true, payload)
+            .await
+        {
+            error!("Unable to publish to '{}': {}", topic, error);
+            return;
+        }
+    }
+}
+
+/// Every state in one payload, unknown states are published as null
+fn json_payload(mqtt_configuration: &MqttConfiguration, snapshot: &TeamsSnapshot) -> Value {
+    let mqtt_entities = &mqtt_configuration.mqtt_entities;
+    let mut payload = Map::new();
+
+    for (field, key) in mqtt_entities.by_field() {
+        let value = if field == TeamsField::Permissions {
+            match &snapshot.permissions {
+                Some(permissions) => permissions.to_json(),
+                None => continue,
+            }
+        } else {
+            json!(snapshot
+                .flag(field)
+                .map(|flag| mqtt_configuration.payload(flag)))
+        };
+
+        payload.insert(key.to_string(), value);
+    }
+
+    Value::Object(payload)
+}
+
+/// One `(topic, payload)` per state that changed since `previous`, every state when it is None
+fn state_messages(
+    mqtt_configuration: &MqttConfiguration,
+    snapshot: &TeamsSnapshot,
+    previous: Option<&TeamsSnapshot>,
+) -> Vec<(String, String)> {
+    let changed = snapshot.diff(previous);
+
+    mqtt_configuration
+        .mqtt_entities
+        .by_field()
+        .into_iter()
+        .filter(|(field, _)| changed.contains(field))
+        .filter_map(|(field, key)| {
+            let payload = if field == TeamsField::Permissions {
+                snapshot.permissions.as_ref()?.to_json().to_string()
+            } else {
+                match snapshot.flag(field) {
+                    Some(flag) => mqtt_configuration.payload(flag).to_string(),
+                    None => mqtt_configuration.payload_unknown.clone(),
+                }
+            };
+
+            Some((mqtt_configuration.state_topic(key), payload))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mqtt::api::{json_payload, state_messages};
+    use crate::mqtt::configuration::create_mqtt_configuration;
+    use crate::teams_ws::states::TeamsSnapshot;
+
+    #[test]
+    fn state_messages_will_only_include_changed_states() {
+        let mut mqtt_configuration = create_mqtt_configuration();
+        mqtt_configuration.payload_on = "1".to_string();
+        mqtt_configuration.payload_off = "0".to_string();
+        let mut previous = TeamsSnapshot::default();
+        previous.is_available = true;
+        previous.is_muted = Some(false);
+        previous.is_video_on = Some(true);
+        let mut snapshot = previous.clone();
+        snapshot.is_muted = Some(true);
+
+        assert_eq!(
+            state_messages(&mqtt_configuration, &snapshot, Some(&previous)),
+            vec![("teams-status/muted".to_string(), "1".to_string())]
+        );
+        assert_eq!(
+            state_messages(&mqtt_configuration, &snapshot, None).len(),
+            8
         );
-        mqtt_options.set_keep_alive(Duration::from_secs(5));
-        let (client, mut event_loop) = AsyncClient::new(mqtt_options, 10);
+    }
+
+    #[test]
+    fn json_payload_will_use_configured_payloads() {
+        let mut mqtt_configuration = create_mqtt_configuration();
+        mqtt_configuration.payload_on = "true".to_string();
+        let mut snapshot = TeamsSnapshot::default();
+        snapshot.is_available = true;
+        snapshot.is_muted = Some(true);
+        snapshot.is_video_on = Some(false);
+        let payload = json_payload(&mqtt_configuration, &snapshot);
 
-        self.client = client;
-        // mqttc requires this to work
-        task::spawn(async move { while let Ok(_) = event_loop.poll().await {} });
+        assert_eq!(payload["muted"], "true");
+        assert_eq!(payload["video_on"], "off");
+        assert!(payload["sharing"].is_null());
+        assert!(payload.get("permissions").is_none());
     }
 }
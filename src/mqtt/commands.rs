@@ -0,0 +1,120 @@
+use crate::mqtt::configuration::MqttConfiguration;
+use crate::teams_ws::commands::{CommandBus, CommandOutcome};
+use log::{error, info, warn};
+use rumqttc::{AsyncClient, Event, Packet, Publish, QoS};
+use tokio::task;
+
+/// Forwards the messages received on the command topics to Teams and publishes each outcome to
+/// the result topic
+pub struct MqttCommands {
+    client: AsyncClient,
+    commands: CommandBus,
+    command_topic: String,
+    result_topic: String,
+}
+
+impl MqttCommands {
+    pub fn new(
+        client: AsyncClient,
+        commands: CommandBus,
+        mqtt_configuration: &MqttConfiguration,
+    ) -> Self {
+        Self {
+            client,
+            commands,
+            command_topic: mqtt_configuration.command_topic(),
+            result_topic: mqtt_configuration.result_topic(),
+        }
+    }
+
+    /// Called for every event of the MQTT event loop, it must not wait on the client as the
+    /// event loop is what empties its queue
+    pub fn handle_event(&self, event: &Event) {
+        match event {
+            // the session is not persisted, so we subscribe again on every connection
+            Event::Incoming(Packet::ConnAck(_)) => {
+                let topic = format!("{}/+", self.command_topic);
+
+                if let Err(error) = self.client.try_subscribe(&topic, QoS::AtLeastOnce) {
+                    error!("Unable to subscribe to '{}': {}", topic, error);
+                }
+            }
+            Event::Incoming(Packet::Publish(publish)) => self.handle_publish(publish),
+            _ => {}
+        }
+    }
+
+    fn handle_publish(&self, publish: &Publish) {
+        let payload = String::from_utf8_lossy(&publish.payload).to_string();
+        let Some(action) = parse_action(&self.command_topic, &publish.topic) else {
+            return;
+        };
+
+        // a retained command would be replayed on every start and every reconnection
+        if publish.retain {
+            warn!(
+                "Ignoring retained MQTT command '{}', commands must not be retained",
+                action
+            );
+            return;
+        }
+
+        info!("Command '{}' received over MQTT", action);
+        let outcome = self.commands.request_action(&action, &payload);
+        let client = self.client.clone();
+        let result_topic = self.result_topic.clone();
+
+        task::spawn(async move {
+            let result = result_payload(&action, &payload, &outcome.await);
+            if let Err(error) = client
+                .publish(result_topic, QoS::AtLeastOnce, false, result)
+                .await
+            {
+                error!("Unable to publish the result of '{}': {}", action, error);
+            }
+        });
+    }
+}
+
+fn parse_action(command_topic: &str, topic: &str) -> Option<String> {
+    topic
+        .strip_prefix(command_topic)?
+        .strip_prefix('/')
+        .map(str::to_string)
+}
+
+fn result_payload(action: &str, parameter: &str, outcome: &CommandOutcome) -> String {
+    outcome.to_json(action, parameter).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mqtt::commands::{parse_action, result_payload};
+    use crate::teams_ws::commands::CommandOutcome;
+    use serde_json::Value;
+
+    #[test]
+    fn parse_action_will_only_accept_command_topics() {
+        assert_eq!(
+            parse_action("teams-status/cmd", "teams-status/cmd/toggle-mute"),
+            Some("toggle-mute".to_string())
+        );
+        assert_eq!(parse_action("teams-status/cmd", "teams-status/cmdx"), None);
+        assert_eq!(
+            parse_action("teams-status/cmd", "other/cmd/toggle-mute"),
+            None
+        );
+    }
+
+    #[test]
+    fn result_payload_failure_will_include_reason() {
+        let outcome = CommandOutcome::Failure("Teams is not connected".to_string());
+        let payload: Value =
+            serde_json::from_str(&result_payload("send-reaction", "like", &outcome)).unwrap();
+
+        assert_eq!(payload["command"], "send-reaction");
+        assert_eq!(payload["parameter"], "like");
+        assert_eq!(payload["result"], "failure");
+        assert_eq!(payload["reason"], "Teams is not connected");
+    }
+}
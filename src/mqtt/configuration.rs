@@ -1,9 +1,26 @@
+use crate::teams_ws::states::TeamsField;
+use crate::utils::computer_name;
+
 pub const MQTT: &str = "MQTT";
 pub const MQTT_URL: &str = "URL";
 pub const MQTT_PORT: &str = "Port";
 pub const MQTT_TOPIC: &str = "Topic";
 pub const MQTT_USERNAME: &str = "Username";
 pub const MQTT_PASSWORD: &str = "Password";
+pub const MQTT_CLIENT_ID: &str = "Client Id";
+pub const MQTT_CA_FILE: &str = "CA File";
+pub const MQTT_CLIENT_CERTIFICATE: &str = "Client Certificate";
+pub const MQTT_CLIENT_KEY: &str = "Client Key";
+pub const MQTT_INSECURE: &str = "Insecure";
+pub const MQTT_COMMAND_TOPIC: &str = "Command Topic";
+pub const MQTT_RESULT_TOPIC: &str = "Result Topic";
+pub const MQTT_AVAILABILITY_TOPIC: &str = "Availability Topic";
+pub const MQTT_PUBLISH_MODE: &str = "Publish Mode";
+pub const MQTT_PAYLOAD_ON: &str = "Payload On";
+pub const MQTT_PAYLOAD_OFF: &str = "Payload Off";
+pub const MQTT_PAYLOAD_UNKNOWN: &str = "Payload Unknown";
+pub const MQTT_DISCOVERY: &str = "Discovery";
+pub const MQTT_DISCOVERY_PREFIX: &str = "Discovery Prefix";
 pub const MQTT_ENTITIES: &str = "MQTT Entities";
 pub const MQTT_MUTED: &str = "Muted";
 pub const MQTT_VIDEO: &str = "Video";
@@ -13,8 +30,64 @@ pub const MQTT_RECORDING: &str = "Recording";
 pub const MQTT_BACKGROUND_BLURRED: &str = "Background Blurred";
 pub const MQTT_SHARING: &str = "Sharing";
 pub const MQTT_UNREAD_MESSAGES: &str = "Unread Messages";
+pub const MQTT_PERMISSIONS: &str = "Permissions";
 pub const MQTT_PORT_DEFAULT: u16 = 1883;
+pub const MQTT_TOPIC_DEFAULT: &str = "teams-status";
+
+/// How to reach the broker, taken from the scheme of the URL (`mqtt://` when there is none)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MqttScheme {
+    Tcp,
+    Tls,
+    Ws,
+    Wss,
+}
+
+impl MqttScheme {
+    const PREFIXES: [(&'static str, MqttScheme); 6] = [
+        ("mqtt://", MqttScheme::Tcp),
+        ("tcp://", MqttScheme::Tcp),
+        ("mqtts://", MqttScheme::Tls),
+        ("ssl://", MqttScheme::Tls),
+        ("ws://", MqttScheme::Ws),
+        ("wss://", MqttScheme::Wss),
+    ];
+}
+
+/// How the states are published: one JSON payload on the topic, one subtopic per state
+/// (ex: `teams-status/muted`), or both
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PublishMode {
+    Json,
+    Topics,
+    Both,
+}
+
+impl PublishMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PublishMode::Json => "json",
+            PublishMode::Topics => "topics",
+            PublishMode::Both => "both",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [PublishMode::Json, PublishMode::Topics, PublishMode::Both]
+            .into_iter()
+            .find(|mode| mode.as_str().eq_ignore_ascii_case(value.trim()))
+    }
+
+    pub fn publishes_json(self) -> bool {
+        self != PublishMode::Topics
+    }
 
+    pub fn publishes_topics(self) -> bool {
+        self != PublishMode::Json
+    }
+}
+
+#[derive(Clone)]
 pub struct MqttEntities {
     pub muted: String,
     pub video: String,
@@ -24,14 +97,53 @@ pub struct MqttEntities {
     pub background_blurred: String,
     pub sharing: String,
     pub unread_messages: String,
+    pub permissions: String,
 }
 
+impl MqttEntities {
+    /// The key of every published field, the permissions are the only one that is not a flag
+    pub fn by_field(&self) -> [(TeamsField, &str); 9] {
+        [
+            (TeamsField::Muted, &self.muted),
+            (TeamsField::VideoOn, &self.video),
+            (TeamsField::HandRaised, &self.hand_raised),
+            (TeamsField::InMeeting, &self.meeting),
+            (TeamsField::RecordingOn, &self.recording),
+            (TeamsField::BackgroundBlurred, &self.background_blurred),
+            (TeamsField::Sharing, &self.sharing),
+            (TeamsField::UnreadMessages, &self.unread_messages),
+            (TeamsField::Permissions, &self.permissions),
+        ]
+    }
+}
+
+#[derive(Clone)]
 pub struct MqttConfiguration {
     url: String,
     pub port: u16,
+    // left blank, a unique id is generated so that computers do not disconnect each other
+    pub client_id: String,
+    // PEM files, the system certificates are trusted when there is no CA file
+    pub ca_file: String,
+    pub client_certificate: String,
+    pub client_key: String,
+    // skips the verification of the broker's certificate, only meant for self-signed test setups
+    pub insecure: bool,
     pub topic: String,
     pub username: String,
     pub password: String,
+    // left blank, they are derived from the topic so that each instance gets its own
+    pub command_topic: String,
+    pub result_topic: String,
+    pub availability_topic: String,
+    pub publish_mode: PublishMode,
+    // the values published for each state, unknown states are null in the JSON payload
+    pub payload_on: String,
+    pub payload_off: String,
+    pub payload_unknown: String,
+    // publish Home Assistant MQTT discovery messages, so entities are created automatically
+    pub discovery: bool,
+    pub discovery_prefix: String,
     pub mqtt_entities: MqttEntities,
 }
 
@@ -40,12 +152,94 @@ impl MqttConfiguration {
         &self.url
     }
 
+    /// Whether the application is running, `offline` is also the last will sent by the broker
+    /// if the application stops without saying so
+    pub fn availability_topic(&self) -> String {
+        if self.availability_topic.is_empty() {
+            format!("{}/availability", self.topic)
+        } else {
+            self.availability_topic.clone()
+        }
+    }
+
+    /// Whether the application is connected to Teams
+    pub fn teams_availability_topic(&self) -> String {
+        format!("{}/teams", self.availability_topic())
+    }
+
+    /// Commands are received on `<command topic>/<action>`, ex: `teams-status/cmd/toggle-mute`
+    pub fn command_topic(&self) -> String {
+        if self.command_topic.is_empty() {
+            format!("{}/cmd", self.topic)
+        } else {
+            self.command_topic.clone()
+        }
+    }
+
+    pub fn result_topic(&self) -> String {
+        if self.result_topic.is_empty() {
+            format!("{}/result", self.topic)
+        } else {
+            self.result_topic.clone()
+        }
+    }
+
+    /// Where HA announces that it (re)started, next to its discovery messages
+    pub fn ha_status_topic(&self) -> String {
+        format!("{}/status", self.discovery_prefix)
+    }
+
+    /// The subtopic of one state when publishing them separately
+    pub fn state_topic(&self, key: &str) -> String {
+        format!("{}/{}", self.topic, key)
+    }
+
+    pub fn payload(&self, flag: bool) -> &str {
+        if flag {
+            &self.payload_on
+        } else {
+            &self.payload_off
+        }
+    }
+
     pub fn set_url(&mut self, url: String) {
-        self.url = if url.to_lowercase().starts_with("mqtt://") {
-            url[7..].to_string()
+        self.url = url;
+    }
+
+    pub fn scheme(&self) -> MqttScheme {
+        let url = self.url.to_lowercase();
+
+        MqttScheme::PREFIXES
+            .into_iter()
+            .find(|(prefix, _)| url.starts_with(prefix))
+            .map_or(MqttScheme::Tcp, |(_, scheme)| scheme)
+    }
+
+    /// The host for TCP and TLS, websockets need the whole URL (ex: `wss://broker/mqtt`)
+    pub fn broker_address(&self) -> String {
+        let url = self.url.to_lowercase();
+
+        match self.scheme() {
+            MqttScheme::Ws | MqttScheme::Wss => self.url.clone(),
+            _ => MqttScheme::PREFIXES
+                .into_iter()
+                .find(|(prefix, _)| url.starts_with(prefix))
+                .map_or(self.url.as_str(), |(prefix, _)| &self.url[prefix.len()..])
+                .trim_end_matches('/')
+                .to_string(),
+        }
+    }
+
+    pub fn client_id(&self) -> String {
+        if self.client_id.is_empty() {
+            format!(
+                "teams-status-{}-{:04x}",
+                computer_name(),
+                rand::random::<u16>()
+            )
         } else {
-            url
-        };
+            self.client_id.clone()
+        }
     }
 }
 
@@ -59,14 +253,54 @@ pub fn create_mqtt_configuration() -> MqttConfiguration {
         background_blurred: "background_blurred".to_string(),
         sharing: "sharing".to_string(),
         unread_messages: "unread_messages".to_string(),
+        permissions: "permissions".to_string(),
     };
 
     MqttConfiguration {
         url: "".to_string(),
-        port: 1883,
-        topic: "teams-status".to_string(),
+        port: MQTT_PORT_DEFAULT,
+        client_id: "".to_string(),
+        ca_file: "".to_string(),
+        client_certificate: "".to_string(),
+        client_key: "".to_string(),
+        insecure: false,
+        topic: MQTT_TOPIC_DEFAULT.to_string(),
         username: "".to_string(),
         password: "".to_string(),
+        command_topic: "".to_string(),
+        result_topic: "".to_string(),
+        availability_topic: "".to_string(),
+        publish_mode: PublishMode::Json,
+        payload_on: "on".to_string(),
+        payload_off: "off".to_string(),
+        payload_unknown: "unknown".to_string(),
+        discovery: true,
+        discovery_prefix: "homeassistant".to_string(),
         mqtt_entities,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::mqtt::configuration::{create_mqtt_configuration, MqttScheme};
+
+    #[test]
+    fn set_url_will_read_scheme_and_address() {
+        let mut mqtt_configuration = create_mqtt_configuration();
+
+        mqtt_configuration.set_url("192.168.1.30".to_string());
+        assert_eq!(mqtt_configuration.scheme(), MqttScheme::Tcp);
+        assert_eq!(mqtt_configuration.broker_address(), "192.168.1.30");
+
+        mqtt_configuration.set_url("MQTTS://broker.local/".to_string());
+        assert_eq!(mqtt_configuration.scheme(), MqttScheme::Tls);
+        assert_eq!(mqtt_configuration.broker_address(), "broker.local");
+
+        mqtt_configuration.set_url("wss://broker.local:8884/mqtt".to_string());
+        assert_eq!(mqtt_configuration.scheme(), MqttScheme::Wss);
+        assert_eq!(
+            mqtt_configuration.broker_address(),
+            "wss://broker.local:8884/mqtt"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use crate::home_assistant::configuration::{create_ha_configuration, HaEntity};
+use crate::mqtt::configuration::{MqttConfiguration, MQTT_TOPIC_DEFAULT};
+use crate::utils::computer_name;
+use serde_json::{json, Value};
+
+const PAYLOAD_ON: &str = "ON";
+const PAYLOAD_OFF: &str = "OFF";
+
+/// The node id groups the entities of this computer, non alphanumeric characters are not allowed.
+/// Other topics get their own node, so that instances sharing a broker do not overwrite each
+/// other's entities, the default one keeps the ids it always had.
+pub fn node_id(mqtt_configuration: &MqttConfiguration) -> String {
+    let node_id = if mqtt_configuration.topic == MQTT_TOPIC_DEFAULT {
+        format!("teams_status_{}", computer_name())
+    } else {
+        format!(
+            "teams_status_{}_{}",
+            computer_name(),
+            mqtt_configuration.topic
+        )
+    };
+
+    node_id
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// The retained `(topic, payload)` config messages that make HA create one binary sensor per
+/// state, names and icons are the defaults of the HA integration
+pub fn discovery_messages(mqtt_configuration: &MqttConfiguration) -> Vec<(String, String)> {
+    let node_id = node_id(mqtt_configuration);
+    let mqtt_entities = &mqtt_configuration.mqtt_entities;
+    let ha_entities = create_ha_configuration().entities;
+    let device_name = if mqtt_configuration.topic == MQTT_TOPIC_DEFAULT {
+        format!("Teams Status ({})", computer_name())
+    } else {
+        format!(
+            "Teams Status ({}, {})",
+            computer_name(),
+            mqtt_configuration.topic
+        )
+    };
+    let device = json!({
+        "identifiers": [node_id],
+        "name": device_name,
+        "manufacturer": "teams-status-rs",
+        "model": "Microsoft Teams",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+
+    [
+        (&mqtt_entities.muted, &ha_entities.is_muted),
+        (&mqtt_entities.video, &ha_entities.is_video_on),
+        (&mqtt_entities.hand_raised, &ha_entities.is_hand_raised),
+        (&mqtt_entities.meeting, &ha_entities.is_in_meeting),
+        (&mqtt_entities.recording, &ha_entities.is_recording_on),
+        (
+            &mqtt_entities.background_blurred,
+            &ha_entities.is_background_blurred,
+        ),
+        (&mqtt_entities.sharing, &ha_entities.is_sharing),
+        (
+            &mqtt_entities.unread_messages,
+            &ha_entities.has_unread_messages,
+        ),
+    ]
+    .into_iter()
+    .map(|(key, ha_entity)| {
+        let mut config = entity_config(mqtt_configuration, &node_id, key, ha_entity);
+        config["device"] = device.clone();
+
+        // the permissions are attributes of the meeting entity, as with the HA integration
+        if key == &mqtt_entities.meeting {
+            if mqtt_configuration.publish_mode.publishes_json() {
+                config["json_attributes_topic"] = json!(mqtt_configuration.topic);
+                config["json_attributes_template"] = json!(format!(
+                    "{{{{ value_json['{}'] | default({{}}) | tojson }}}}",
+                    mqtt_entities.permissions
+                ));
+            } else {
+                config["json_attributes_topic"] =
+                    json!(mqtt_configuration.state_topic(&mqtt_entities.permissions));
+            }
+        }
+
+        let topic = format!(
+            "{}/binary_sensor/{}/{}/config",
+            mqtt_configuration.discovery_prefix, node_id, key
+        );
+        (topic, config.to_string())
+    })
+    .collect()
+}
+
+fn entity_config(
+    mqtt_configuration: &MqttConfiguration,
+    node_id: &str,
+    key: &str,
+    ha_entity: &HaEntity,
+) -> Value {
+    let object_id = ha_entity
+        .id
+        .strip_prefix("binary_sensor.")
+        .unwrap_or(&ha_entity.id);
+
+    // the JSON payload is preferred when both are published, it holds every state at once
+    let (state_topic, state) = if mqtt_configuration.publish_mode.publishes_json() {
+        (
+            mqtt_configuration.topic.clone(),
+            format!("value_json['{}']", key),
+        )
+    } else {
+        (mqtt_configuration.state_topic(key), "value".to_string())
+    };
+
+    json!({
+        "name": ha_entity.friendly_name,
+        "unique_id": format!("{}_{}", node_id, key),
+        "object_id": object_id,
+        "state_topic": state_topic,
+        // anything that is neither on nor off is unknown, which HA shows with 'None'
+        "value_template": format!(
+            "{{% set state = {state} %}}{{{{ '{on}' if state == '{payload_on}' else '{off}' if state == '{payload_off}' else 'None' }}}}",
+            state = state,
+            on = PAYLOAD_ON,
+            off = PAYLOAD_OFF,
+            payload_on = mqtt_configuration.payload_on,
+            payload_off = mqtt_configuration.payload_off
+        ),
+        "payload_on": PAYLOAD_ON,
+        "payload_off": PAYLOAD_OFF,
+        // the states are only current while both the application and Teams are running
+        "availability": [
+            { "topic": mqtt_configuration.availability_topic() },
+            { "topic": mqtt_configuration.teams_availability_topic() },
+        ],
+        "availability_mode": "all",
+        "icon": ha_entity.icons.on,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mqtt::configuration::{create_mqtt_configuration, PublishMode};
+    use crate::mqtt::discovery::{discovery_messages, node_id};
+    use serde_json::Value;
+
+    #[test]
+    fn discovery_messages_will_describe_every_state() {
+        let mqtt_configuration = create_mqtt_configuration();
+        let messages = discovery_messages(&mqtt_configuration);
+        let (topic, payload) = &messages[0];
+        let config: Value = serde_json::from_str(payload).unwrap();
+
+        assert_eq!(messages.len(), 8);
+        assert_eq!(
+            topic,
+            &format!(
+                "homeassistant/binary_sensor/{}/muted/config",
+                node_id(&mqtt_configuration)
+            )
+        );
+        assert_eq!(config["state_topic"], "teams-status");
+        assert_eq!(
+            config["availability"][0]["topic"],
+            "teams-status/availability"
+        );
+        assert_eq!(
+            config["availability"][1]["topic"],
+            "teams-status/availability/teams"
+        );
+        assert_eq!(config["object_id"], "teams_muted");
+        assert_eq!(config["icon"], "mdi:microphone");
+        assert_eq!(
+            config["value_template"],
+            "{% set state = value_json['muted'] %}{{ 'ON' if state == 'on' else 'OFF' if state == 'off' else 'None' }}"
+        );
+    }
+
+    #[test]
+    fn node_id_other_topic_will_be_unique() {
+        let mut office = create_mqtt_configuration();
+        office.topic = "office/teams".to_string();
+
+        assert_ne!(node_id(&office), node_id(&create_mqtt_configuration()));
+        assert!(node_id(&office).ends_with("_office_teams"));
+    }
+
+    #[test]
+    fn discovery_messages_topics_mode_will_use_state_topics() {
+        let mut mqtt_configuration = create_mqtt_configuration();
+        mqtt_configuration.publish_mode = PublishMode::Topics;
+        mqtt_configuration.payload_on = "1".to_string();
+        mqtt_configuration.payload_off = "0".to_string();
+        let messages = discovery_messages(&mqtt_configuration);
+        let config: Value = serde_json::from_str(&messages[0].1).unwrap();
+
+        assert_eq!(config["state_topic"], "teams-status/muted");
+        assert_eq!(
+            config["value_template"],
+            "{% set state = value %}{{ 'ON' if state == '1' else 'OFF' if state == '0' else 'None' }}"
+        );
+    }
+}
@@ -1,2 +1,5 @@
 pub mod api;
+pub mod commands;
 pub mod configuration;
+pub mod discovery;
+pub mod transport;
@@ -0,0 +1,154 @@
+use crate::mqtt::configuration::{MqttConfiguration, MqttScheme};
+use anyhow::{anyhow, Context};
+use log::info;
+use rumqttc::{TlsConfiguration, Transport};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+/// The transport matching the scheme of the URL, certificates are loaded here so that a
+/// missing file is reported when the listener is created
+pub fn create_transport(mqtt_configuration: &MqttConfiguration) -> anyhow::Result<Transport> {
+    Ok(match mqtt_configuration.scheme() {
+        MqttScheme::Tcp => Transport::Tcp,
+        MqttScheme::Tls => Transport::tls_with_config(tls_configuration(mqtt_configuration)?),
+        MqttScheme::Ws => Transport::Ws,
+        MqttScheme::Wss => Transport::wss_with_config(tls_configuration(mqtt_configuration)?),
+    })
+}
+
+fn tls_configuration(mqtt_configuration: &MqttConfiguration) -> anyhow::Result<TlsConfiguration> {
+    let builder = ClientConfig::builder();
+    let builder = if mqtt_configuration.insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification::default()))
+    } else {
+        builder.with_root_certificates(root_certificates(&mqtt_configuration.ca_file)?)
+    };
+
+    let client_config = if mqtt_configuration.client_certificate.is_empty() {
+        builder.with_no_client_auth()
+    } else {
+        let certificates = load_certificates(&mqtt_configuration.client_certificate)?;
+        let key = load_private_key(&mqtt_configuration.client_key)?;
+        builder.with_client_auth_cert(certificates, key)?
+    };
+
+    Ok(TlsConfiguration::Rustls(Arc::new(client_config)))
+}
+
+fn root_certificates(ca_file: &str) -> anyhow::Result<RootCertStore> {
+    let mut root_certificates = RootCertStore::empty();
+
+    if ca_file.is_empty() {
+        // system stores often hold a few certificates rustls cannot parse, they are skipped
+        let certificates = rustls_native_certs::load_native_certs()
+            .context("Unable to load the system certificates")?;
+        let (_, ignored) = root_certificates.add_parsable_certificates(certificates);
+        if ignored > 0 {
+            info!(
+                "Ignored {} system certificates that could not be parsed",
+                ignored
+            );
+        }
+    } else {
+        // a certificate the user picked has to be valid
+        for certificate in load_certificates(ca_file)? {
+            root_certificates
+                .add(certificate)
+                .with_context(|| format!("Invalid certificate in '{}'", ca_file))?;
+        }
+    }
+
+    Ok(root_certificates)
+}
+
+fn open(path: &str) -> anyhow::Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("Unable to open '{}'", path))?;
+    Ok(BufReader::new(file))
+}
+
+fn load_certificates(path: &str) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certificates = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Unable to read the certificates of '{}'", path))?;
+
+    if certificates.is_empty() {
+        return Err(anyhow!("No certificate found in '{}'", path));
+    }
+
+    Ok(certificates)
+}
+
+fn load_private_key(path: &str) -> anyhow::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .with_context(|| format!("Unable to read the private key of '{}'", path))?
+        .ok_or_else(|| anyhow!("No private key found in '{}'", path))
+}
+
+/// Accepts any certificate from the broker, the signatures are still checked so that the
+/// handshake itself is valid
+#[derive(Debug)]
+struct NoVerification {
+    provider: CryptoProvider,
+}
+
+impl Default for NoVerification {
+    fn default() -> Self {
+        Self {
+            provider: rustls::crypto::ring::default_provider(),
+        }
+    }
+}
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -1,208 +1,465 @@
-use crate::teams_ws::configuration::{
-    change_teams_configuration, TeamsConfiguration, TEAMS, TEAMS_API_TOKEN,
-};
-use crate::teams_ws::states::TeamsStates;
-use crate::traits::Listener;
-use anyhow::Context;
-use futures_util::{future, pin_mut, SinkExt, StreamExt};
-use json::JsonValue;
-use log::{error, info};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::Mutex;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
-
-const JSON_MEETING_UPDATE: &str = "meetingUpdate";
-const JSON_MEETING_STATE: &str = "meetingState";
-const JSON_IS_MUTED: &str = "isMuted";
-const JSON_IS_VIDEO_ON: &str = "isVideoOn";
-const JSON_IS_HAND_RAISED: &str = "isHandRaised";
-const JSON_IS_IN_MEETING: &str = "isInMeeting";
-const JSON_IS_RECORDING_ON: &str = "isRecordingOn";
-const JSON_IS_BACKGROUND_BLURRED: &str = "isBackgroundBlurred";
-const JSON_IS_SHARING: &str = "isSharing";
-const JSON_HAS_UNREAD_MESSAGES: &str = "hasUnreadMessages";
-const JSON_TOKEN_REFRESH: &str = "tokenRefresh";
+use crate::connection::{ConnectionState, ConnectionStatus};
+use crate::teams_ws::commands::{CommandOutcome, CommandReceiver, CommandRequest, TeamsCommand};
+use crate::teams_ws::configuration::{TeamsConfiguration, TEAMS};
+use crate::teams_ws::pairing::{Pairing, PairingState};
+use crate::teams_ws::protocol::{create_protocol, ClientIdentity};
+use crate::teams_ws::states::TeamsSnapshot;
+use crate::traits::TeamsProtocol;
+use anyhow::{anyhow, Context};
+use futures_util::{future, pin_mut, Sink, SinkExt, StreamExt};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, watch};
+use tokio_tungstenite::{connect_async, tungstenite, tungstenite::protocol::Message};
+
+const RESPONSE_SUCCESS: &str = "Success";
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+// how long the user has to allow the application once Teams prompted them
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(60);
+// connections closed by Teams before it sent anything, in a row and for every protocol version,
+// before the token is reported as possibly rejected
+const TOKEN_REJECTION_THRESHOLD: u32 = 2;
+
+/// Commands sent to Teams that are waiting for their `{"requestId":N,"response":...}` answer
+#[derive(Default)]
+struct PendingCommands {
+    next_request_id: AtomicU32,
+    requests: std::sync::Mutex<HashMap<u32, (Instant, CommandRequest)>>,
+}
+
+impl PendingCommands {
+    fn register(&self, request: CommandRequest) -> u32 {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.requests
+            .lock()
+            .unwrap()
+            .insert(request_id, (Instant::now(), request));
+        request_id
+    }
+
+    fn resolve(&self, request_id: u32, outcome: CommandOutcome) {
+        let Some((_, request)) = self.requests.lock().unwrap().remove(&request_id) else {
+            info!("Received a response for unknown request {}", request_id);
+            return;
+        };
+
+        log_outcome(&request, request_id, &outcome);
+        request.respond(outcome);
+    }
+
+    fn expire(&self) {
+        let expired: Vec<(u32, CommandRequest)> = {
+            let mut requests = self.requests.lock().unwrap();
+            let expired_ids: Vec<u32> = requests
+                .iter()
+                .filter(|(_, (sent_at, _))| sent_at.elapsed() >= COMMAND_TIMEOUT)
+                .map(|(request_id, _)| *request_id)
+                .collect();
+
+            expired_ids
+                .into_iter()
+                .filter_map(|request_id| {
+                    requests
+                        .remove(&request_id)
+                        .map(|(_, request)| (request_id, request))
+                })
+                .collect()
+        };
+
+        for (request_id, request) in expired {
+            log_outcome(&request, request_id, &CommandOutcome::Timeout);
+            request.respond(CommandOutcome::Timeout);
+        }
+    }
+
+    /// Teams cannot answer on another connection, so nothing left is worth waiting for
+    fn fail_all(&self, reason: &str) {
+        let requests: Vec<(u32, CommandRequest)> = self
+            .requests
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(request_id, (_, request))| (request_id, request))
+            .collect();
+
+        for (request_id, request) in requests {
+            let outcome = CommandOutcome::Failure(reason.to_string());
+            log_outcome(&request, request_id, &outcome);
+            request.respond(outcome);
+        }
+    }
+}
+
+fn log_outcome(request: &CommandRequest, request_id: u32, outcome: &CommandOutcome) {
+    match outcome {
+        CommandOutcome::Success => info!(
+            "Teams accepted '{}' (request {})",
+            request.command.action(),
+            request_id
+        ),
+        _ => error!(
+            "Teams did not accept '{}' (request {}): {}",
+            request.command.action(),
+            request_id,
+            outcome
+        ),
+    }
+}
 
 pub struct TeamsAPI {
-    pub teams_states: Arc<TeamsStates>,
-    pub url: String,
+    // every consumer subscribes to it and compares snapshots on its own
+    snapshot: watch::Sender<TeamsSnapshot>,
+    url: String,
+    identity: ClientIdentity,
+    // in order of preference, the next one is tried when Teams refuses a connection
+    protocols: Vec<Box<dyn TeamsProtocol>>,
+    protocol_index: AtomicUsize,
+    // once Teams sent something the protocol is kept, it also closes silently while restarting
+    protocol_detected: AtomicBool,
+    pairing: Pairing,
+    rejected_connections: AtomicU32,
+    pending_commands: PendingCommands,
+    status: ConnectionStatus,
+    ping_interval: Option<Duration>,
+    ping_timeout: Duration,
 }
 
 impl TeamsAPI {
-    pub fn new(conf: &TeamsConfiguration) -> Self {
-        let teams_states = Arc::new(TeamsStates {
-            is_muted: AtomicBool::new(false),
-            prev_is_muted: AtomicBool::new(false),
-            is_video_on: AtomicBool::new(false),
-            prev_is_video_on: AtomicBool::new(false),
-            is_hand_raised: AtomicBool::new(false),
-            prev_is_hand_raised: AtomicBool::new(false),
-            is_in_meeting: AtomicBool::new(false),
-            prev_is_in_meeting: AtomicBool::new(false),
-            is_recording_on: AtomicBool::new(false),
-            prev_is_recording_on: AtomicBool::new(false),
-            is_background_blurred: AtomicBool::new(false),
-            prev_is_background_blurred: AtomicBool::new(false),
-            is_sharing: AtomicBool::new(false),
-            prev_is_sharing: AtomicBool::new(false),
-            has_unread_messages: AtomicBool::new(false),
-            prev_has_unread_messages: AtomicBool::new(false),
-        });
-
-        let api_token = if !conf.api_token.is_empty() {
-            format!("token={}&", &conf.api_token)
-        } else {
-            "".to_string()
-        };
-        let url = format!(
-            "{url}?{api_token}protocol-version=2.0.0&manufacturer=HA-Integration&device=MyPC&app=teams-status-rs&app-version=1.0",
-            url = conf.url,
-            api_token = api_token);
+    pub fn new(conf: &TeamsConfiguration, status: ConnectionStatus, pairing: Pairing) -> Self {
+        pairing.initialize(&conf.api_token);
+
+        Self {
+            snapshot: watch::Sender::new(TeamsSnapshot::default()),
+            url: conf.url.clone(),
+            identity: ClientIdentity::new(conf),
+            protocols: conf
+                .protocol_versions()
+                .iter()
+                .map(|version| create_protocol(version))
+                .collect(),
+            protocol_index: AtomicUsize::new(0),
+            protocol_detected: AtomicBool::new(false),
+            pairing,
+            rejected_connections: AtomicU32::new(0),
+            pending_commands: PendingCommands::default(),
+            status,
+            ping_interval: (conf.ping_interval > 0)
+                .then(|| Duration::from_secs(conf.ping_interval)),
+            ping_timeout: Duration::from_secs(conf.ping_timeout),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<TeamsSnapshot> {
+        self.snapshot.subscribe()
+    }
+
+    pub async fn start_listening(&self, commands: &mut CommandReceiver) -> anyhow::Result<()> {
+        self.status.set(TEAMS, ConnectionState::Connecting);
+        let result = self.listen(commands).await;
+        self.pending_commands.fail_all("Connection to Teams lost");
+
+        // Teams closed, dropped the connection or could not be reached, consumers only see it
+        // once so that they do not get flooded while we retry
+        if self
+            .snapshot
+            .send_if_modified(TeamsSnapshot::set_unavailable)
+        {
+            info!("Teams is unavailable");
+        }
 
-        Self { teams_states, url }
+        result
     }
 
-    pub async fn start_listening(
+    async fn send_command<S>(
         &self,
-        listener: Arc<Mutex<Box<dyn Listener>>>,
-        is_running: Arc<AtomicBool>,
-        toggle_mute: Arc<AtomicBool>,
-    ) -> anyhow::Result<()> {
-        let url_local = url::Url::parse(&self.url)?;
+        write: &mut S,
+        request: CommandRequest,
+        protocol: &dyn TeamsProtocol,
+    ) where
+        S: Sink<Message, Error = tungstenite::Error> + Unpin,
+    {
+        let command = request.command;
+
+        // commands sent while Teams was unreachable are no longer relevant
+        if request.queued_at.elapsed() >= COMMAND_TIMEOUT {
+            warn!(
+                "Dropping '{}' as it was sent too long ago",
+                command.action()
+            );
+            request.respond(CommandOutcome::Timeout);
+            return;
+        }
+
+        let permissions = self.snapshot.borrow().permissions.clone();
+        if permissions.is_some_and(|permissions| !permissions.allows(command)) {
+            warn!("Teams does not currently allow '{}'", command.action());
+            request.respond(CommandOutcome::Failure(
+                "Not permitted by Teams at the moment".to_string(),
+            ));
+            return;
+        }
+
+        if !protocol.supports(command) {
+            warn!(
+                "'{}' is not supported by protocol {}",
+                command.action(),
+                protocol.version()
+            );
+            request.respond(CommandOutcome::Failure(format!(
+                "Not supported by Teams protocol {}",
+                protocol.version()
+            )));
+            return;
+        }
+
+        let request_id = self.pending_commands.register(request);
+        info!(
+            "Sending '{}' to Teams (request {})",
+            command.action(),
+            request_id
+        );
+        let msg = Message::text(protocol.command_message(command, request_id, &self.identity));
+
+        match write.send(msg).await {
+            Err(error) => self
+                .pending_commands
+                .resolve(request_id, CommandOutcome::Failure(error.to_string())),
+            // nothing else is coming, so sending it is all we can confirm
+            Ok(()) if !protocol.confirms_commands() => self
+                .pending_commands
+                .resolve(request_id, CommandOutcome::Success),
+            Ok(()) => {}
+        }
+    }
+
+    fn protocol(&self) -> &dyn TeamsProtocol {
+        let index = self.protocol_index.load(Ordering::Relaxed);
+        self.protocols[index % self.protocols.len()].as_ref()
+    }
+
+    async fn listen(&self, commands: &mut CommandReceiver) -> anyhow::Result<()> {
+        // the token and protocol change over time, so the url is built for every connection
+        let protocol = self.protocol();
+        let api_token = self.pairing.token();
+        let url_local = protocol
+            .connection_url(&self.url, &self.identity, &api_token)
+            .inspect_err(|_| {
+                // the other protocols may still work, ex: the classic one without its token
+                self.protocol_index.fetch_add(1, Ordering::Relaxed);
+            })?;
         let (ws_stream, _) = connect_async(url_local.as_str())
             .await
             .with_context(|| "Failed to connect")?;
+        self.status.set(TEAMS, ConnectionState::Connected);
         let (mut write, read) = ws_stream.split();
-        let force_update = Arc::new(AtomicBool::new(true));
+        // any message (including pongs) proves the connection is alive
+        let last_received = std::sync::Mutex::new(Instant::now());
+        let received_message = AtomicBool::new(false);
         let ws_to_parser = {
             read.for_each(|message| async {
                 if message.is_ok() {
-                    let data = &message.unwrap().into_data();
+                    *last_received.lock().unwrap() = Instant::now();
+                    let message = message.unwrap();
+
+                    if !(message.is_text() || message.is_binary()) {
+                        return;
+                    }
+
+                    received_message.store(true, Ordering::Relaxed);
+
+                    let data = &message.into_data();
                     let json = String::from_utf8_lossy(data);
                     info!("{}", json);
 
-                    let parse_result = parse_data_and_notify_listener(
+                    parse_data_and_publish(
                         &json,
-                        listener.clone(),
-                        self.teams_states.clone(),
-                        force_update.clone(),
-                    )
-                    .await;
-
-                    if parse_result.is_err() {
-                        error!(
-                            "Unable to parse or notify listener, abandoning: {}",
-                            parse_result.unwrap_err()
-                        );
-                    }
+                        &self.snapshot,
+                        &self.pending_commands,
+                        &self.pairing,
+                        protocol,
+                    );
                 }
             })
         };
 
         let running_future = async {
-            let one_second = Duration::from_secs(1);
+            let mut ticker = tokio::time::interval(Duration::from_secs(1));
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut ping_sent_at: Option<Instant> = None;
+            let mut pair_outcome: Option<oneshot::Receiver<CommandOutcome>> = None;
+            let mut pair_deadline: Option<Instant> = None;
 
-            while is_running.load(Ordering::Relaxed) {
-                tokio::time::sleep(one_second).await;
+            loop {
+                tokio::select! {
+                    request = commands.recv() => match request {
+                        Some(request) => self.send_command(&mut write, request, protocol).await,
+                        None => break Ok(()),
+                    },
+                    _ = ticker.tick() => {}
+                }
 
-                if toggle_mute.load(Ordering::Relaxed) {
-                    let msg = Message::text(
-                        r#"{"requestId":1,"apiVersion":"2.0.0","action":"toggle-mute"}"#,
-                    );
+                // a half-open socket (ex: after the computer wakes up) is only noticed by asking
+                if let Some(ping_interval) = self.ping_interval {
+                    let last_received = *last_received.lock().unwrap();
 
-                    write.send(msg).await.unwrap();
-                    toggle_mute.swap(false, Ordering::Relaxed);
+                    match ping_sent_at {
+                        Some(sent_at) if last_received >= sent_at => ping_sent_at = None,
+                        Some(sent_at) if sent_at.elapsed() >= self.ping_timeout => {
+                            warn!(
+                                "Teams did not answer for {:?}, dropping the connection",
+                                last_received.elapsed()
+                            );
+                            break Err(anyhow!("Teams did not answer ping"));
+                        }
+                        Some(_) => {}
+                        None if last_received.elapsed() >= ping_interval => {
+                            ping_sent_at = Some(Instant::now());
+                            if let Err(error) = write.send(Message::Ping(Default::default())).await
+                            {
+                                warn!("Unable to ping Teams, dropping the connection: {}", error);
+                                break Err(anyhow!("Unable to ping Teams: {}", error));
+                            }
+                        }
+                        None => {}
+                    }
                 }
-            }
 
-            info!("Application close requested");
-        };
+                self.pending_commands.expire();
 
-        pin_mut!(running_future, ws_to_parser);
-        future::select(running_future, ws_to_parser).await;
-        Ok(())
-    }
-}
+                // Teams only offers pairing during a meeting, it then prompts the user
+                if self.pairing.is_requested() {
+                    let can_pair = self
+                        .snapshot
+                        .borrow()
+                        .permissions
+                        .as_ref()
+                        .is_some_and(|permissions| permissions.can_pair == Some(true));
 
-async fn update_value(
-    teams_state_value: &AtomicBool,
-    answer: &JsonValue,
-    json_value_name: &str,
-) -> bool {
-    let new_value = answer[JSON_MEETING_UPDATE][JSON_MEETING_STATE][json_value_name]
-        .as_bool()
-        .unwrap_or_else(|| {
-            error!("Unable to locate {} variable in JSON", json_value_name);
-            false
-        });
-
-    teams_state_value.swap(new_value, Ordering::Relaxed) != new_value
-}
+                    if !protocol.supports(TeamsCommand::Pair) {
+                        self.pairing.take_request();
+                        self.pairing.pairing_failed(&format!(
+                            "protocol {} uses the API Token from the Teams settings",
+                            protocol.version()
+                        ));
+                    } else if can_pair && self.pairing.take_request() {
+                        self.pairing.set_state(PairingState::WaitingForApproval);
+                        let (responder, receiver) = oneshot::channel();
+                        pair_outcome = Some(receiver);
+                        pair_deadline = Some(Instant::now() + PAIRING_TIMEOUT);
+                        let request = CommandRequest {
+                            command: TeamsCommand::Pair,
+                            queued_at: Instant::now(),
+                            responder,
+                        };
+                        self.send_command(&mut write, request, protocol).await;
+                    }
+                }
 
-async fn parse_data_and_notify_listener(
-    json: &str,
-    listener: Arc<Mutex<Box<dyn Listener>>>,
-    teams_states: Arc<TeamsStates>,
-    force_update: Arc<AtomicBool>,
-) -> anyhow::Result<()> {
-    let answer = json::parse(&json.to_string()).unwrap_or(json::parse("{}")?);
-
-    if answer.has_key(JSON_MEETING_UPDATE) {
-        let mut has_changed = update_value(&teams_states.is_muted, &answer, JSON_IS_MUTED).await;
-        has_changed |= update_value(&teams_states.is_video_on, &answer, JSON_IS_VIDEO_ON).await;
-        has_changed |=
-            update_value(&teams_states.is_hand_raised, &answer, JSON_IS_HAND_RAISED).await;
-        has_changed |= update_value(&teams_states.is_in_meeting, &answer, JSON_IS_IN_MEETING).await;
-        has_changed |=
-            update_value(&teams_states.is_recording_on, &answer, JSON_IS_RECORDING_ON).await;
-        has_changed |= update_value(
-            &teams_states.is_background_blurred,
-            &answer,
-            JSON_IS_BACKGROUND_BLURRED,
-        )
-        .await;
-        has_changed |= update_value(&teams_states.is_sharing, &answer, JSON_IS_SHARING).await;
-        has_changed |= update_value(
-            &teams_states.has_unread_messages,
-            &answer,
-            JSON_HAS_UNREAD_MESSAGES,
-        )
-        .await;
-
-        let force_update = force_update.swap(false, Ordering::Relaxed);
-
-        if force_update || has_changed {
-            // Issue!: This will only run once regardless of MAX_RETRIES
-            // for some reason after a reconnect the notify_changed will get a pass no matter what
-            const MAX_RETRIES: i32 = 3;
-            for i in 1..MAX_RETRIES {
-                let result = listener
-                    .lock()
-                    .await
-                    .notify_changed(&teams_states, force_update)
-                    .await;
-
-                if result.is_ok() || (i == MAX_RETRIES) {
-                    result?;
-                    break;
+                // the user may take longer than the command timeout to answer the prompt, so
+                // only an explicit refusal ends the wait for the token
+                if let Some(receiver) = &mut pair_outcome {
+                    match receiver.try_recv() {
+                        Ok(CommandOutcome::Failure(reason)) => {
+                            self.pairing.pairing_failed(&reason);
+                            pair_outcome = None;
+                        }
+                        Err(oneshot::error::TryRecvError::Empty) => {}
+                        _ => pair_outcome = None,
+                    }
                 }
-                // we will try to reconnect if the connection failed
-                else if i < MAX_RETRIES {
-                    error!("{}: Reconnecting and retrying...", result.unwrap_err());
-                    tokio::time::sleep(Duration::from_secs(1)).await;
-                    listener.lock().await.reconnect();
+
+                // the prompt may be ignored or closed by Teams, which does not tell us
+                if let Some(deadline) = pair_deadline {
+                    if self.pairing.state() != PairingState::WaitingForApproval {
+                        pair_deadline = None;
+                    } else if Instant::now() >= deadline {
+                        self.pairing
+                            .pairing_failed("the application was not allowed in Teams in time");
+                        pair_outcome = None;
+                        pair_deadline = None;
+                    }
                 }
             }
+        };
+
+        pin_mut!(running_future, ws_to_parser);
+        // the keepalive ends the connection with an error, Teams closing it ends the parser
+        let (closed_by_teams, result) = match future::select(running_future, ws_to_parser).await {
+            future::Either::Left((result, _)) => (false, result),
+            future::Either::Right(_) => (true, Ok(())),
+        };
+
+        // the token is only sent on the connection that asked for it
+        if self.pairing.state() == PairingState::WaitingForApproval {
+            self.pairing
+                .pairing_failed("the connection to Teams was lost while waiting for approval");
         }
-    } else if answer.has_key(JSON_TOKEN_REFRESH) && !answer[JSON_TOKEN_REFRESH].is_empty() {
-        change_teams_configuration(
-            TEAMS,
-            TEAMS_API_TOKEN,
-            &answer[JSON_TOKEN_REFRESH].to_string(),
-        )
+
+        if received_message.load(Ordering::Relaxed) {
+            self.protocol_detected.store(true, Ordering::Relaxed);
+            self.rejected_connections.store(0, Ordering::Relaxed);
+            self.pairing.connection_accepted();
+        } else if closed_by_teams {
+            // Teams drops connections it does not accept without a word, because of either the
+            // protocol version or the token
+            let rejections = self.rejected_connections.fetch_add(1, Ordering::Relaxed) + 1;
+
+            if self.protocols.len() > 1 && !self.protocol_detected.load(Ordering::Relaxed) {
+                self.protocol_index.fetch_add(1, Ordering::Relaxed);
+                info!(
+                    "Teams did not accept protocol version {}, trying {}",
+                    protocol.version(),
+                    self.protocol().version()
+                );
+            }
+
+            // a token copied from the Teams settings is left alone, only paired ones are reported
+            let threshold = TOKEN_REJECTION_THRESHOLD * self.protocols.len() as u32;
+            let is_paired_token = self
+                .protocols
+                .iter()
+                .any(|protocol| protocol.supports(TeamsCommand::Pair));
+            if !api_token.is_empty() && is_paired_token && rejections >= threshold {
+                self.rejected_connections.store(0, Ordering::Relaxed);
+                self.pairing.token_possibly_rejected();
+            }
+
+            return Err(anyhow!(
+                "Teams closed the connection before sending anything"
+            ));
+        }
+
+        result
     }
+}
+
+fn parse_data_and_publish(
+    json: &str,
+    snapshot: &watch::Sender<TeamsSnapshot>,
+    pending_commands: &PendingCommands,
+    pairing: &Pairing,
+    protocol: &dyn TeamsProtocol,
+) {
+    let message = protocol.parse_message(json).unwrap_or_else(|error| {
+        error!("Unable to parse message from Teams: {}", error);
+        Default::default()
+    });
 
-    Ok(())
+    if let (Some(request_id), Some(response)) = (message.request_id, &message.response) {
+        let outcome = if response == RESPONSE_SUCCESS {
+            CommandOutcome::Success
+        } else {
+            CommandOutcome::Failure(message.error_msg.unwrap_or(response.to_string()))
+        };
+
+        pending_commands.resolve(request_id, outcome);
+    } else if let Some(meeting_update) = message.meeting_update {
+        // consumers are only woken up when something changed
+        snapshot.send_if_modified(|snapshot| snapshot.apply(meeting_update));
+    } else if let Some(token_refresh) = message.token_refresh.filter(|token| !token.is_empty()) {
+        info!("Received a new token from Teams");
+        pairing.token_refreshed(&token_refresh);
+    }
 }
@@ -0,0 +1,335 @@
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::fmt;
+use std::future::Future;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reaction {
+    Like,
+    Love,
+    Applause,
+    Laugh,
+    Wow,
+}
+
+impl Reaction {
+    pub const ALL: [Reaction; 5] = [
+        Reaction::Like,
+        Reaction::Love,
+        Reaction::Applause,
+        Reaction::Laugh,
+        Reaction::Wow,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Reaction::Like => "like",
+            Reaction::Love => "love",
+            Reaction::Applause => "applause",
+            Reaction::Laugh => "laugh",
+            Reaction::Wow => "wow",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Reaction::ALL
+            .into_iter()
+            .find(|reaction| reaction.as_str().eq_ignore_ascii_case(value.trim()))
+    }
+}
+
+/// Every action the local Teams API accepts, see the readme for the message format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TeamsCommand {
+    ToggleMute,
+    ToggleVideo,
+    ToggleHand,
+    ToggleBackgroundBlur,
+    LeaveCall,
+    StopSharing,
+    SendReaction(Reaction),
+    QueryState,
+    Pair,
+}
+
+impl TeamsCommand {
+    pub fn action(self) -> &'static str {
+        match self {
+            TeamsCommand::ToggleMute => "toggle-mute",
+            TeamsCommand::ToggleVideo => "toggle-video",
+            TeamsCommand::ToggleHand => "toggle-hand",
+            TeamsCommand::ToggleBackgroundBlur => "toggle-background-blur",
+            TeamsCommand::LeaveCall => "leave-call",
+            TeamsCommand::StopSharing => "stop-sharing",
+            TeamsCommand::SendReaction(_) => "send-reaction",
+            TeamsCommand::QueryState => "query-state",
+            TeamsCommand::Pair => "pair",
+        }
+    }
+
+    /// Parses an action name as sent to Teams (ex: `toggle-mute`), `parameter` is only used by
+    /// actions that need one, like the reaction type of `send-reaction`. `pair` is left out as
+    /// pairing is requested through `Pairing`, which tracks its progress
+    pub fn parse(action: &str, parameter: &str) -> Option<Self> {
+        match action.trim().to_lowercase().as_str() {
+            "toggle-mute" => Some(TeamsCommand::ToggleMute),
+            "toggle-video" => Some(TeamsCommand::ToggleVideo),
+            "toggle-hand" => Some(TeamsCommand::ToggleHand),
+            "toggle-background-blur" => Some(TeamsCommand::ToggleBackgroundBlur),
+            "leave-call" => Some(TeamsCommand::LeaveCall),
+            "stop-sharing" => Some(TeamsCommand::StopSharing),
+            "send-reaction" => Reaction::parse(parameter).map(TeamsCommand::SendReaction),
+            "query-state" => Some(TeamsCommand::QueryState),
+            _ => None,
+        }
+    }
+
+    fn parameters(self) -> Option<Value> {
+        match self {
+            TeamsCommand::SendReaction(reaction) => Some(json!({ "type": reaction.as_str() })),
+            _ => None,
+        }
+    }
+
+    pub fn to_message(self, request_id: u32, api_version: &str) -> String {
+        let mut message = json!({
+            "requestId": request_id,
+            "apiVersion": api_version,
+            "action": self.action(),
+        });
+
+        if let Some(parameters) = self.parameters() {
+            message["parameters"] = parameters;
+        }
+
+        message.to_string()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommandOutcome {
+    Success,
+    Failure(String),
+    Timeout,
+}
+
+impl CommandOutcome {
+    /// The outcome as reported back to the producer of the command (MQTT, Home Assistant)
+    pub fn to_json(&self, action: &str, parameter: &str) -> Value {
+        let (result, reason) = match self {
+            CommandOutcome::Success => ("success", None),
+            CommandOutcome::Failure(reason) => ("failure", Some(reason.as_str())),
+            CommandOutcome::Timeout => ("timeout", None),
+        };
+
+        json!({
+            "command": action,
+            "parameter": parameter,
+            "result": result,
+            "reason": reason,
+        })
+    }
+}
+
+impl fmt::Display for CommandOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandOutcome::Success => write!(f, "success"),
+            CommandOutcome::Failure(reason) => write!(f, "failure ({})", reason),
+            CommandOutcome::Timeout => write!(f, "timeout"),
+        }
+    }
+}
+
+pub struct CommandRequest {
+    pub command: TeamsCommand,
+    pub queued_at: Instant,
+    pub responder: oneshot::Sender<CommandOutcome>,
+}
+
+impl CommandRequest {
+    pub fn respond(self, outcome: CommandOutcome) {
+        // the caller may have stopped waiting, which is fine
+        let _ = self.responder.send(outcome);
+    }
+}
+
+enum BusMessage {
+    Command(CommandRequest),
+    Shutdown,
+}
+
+/// Carries commands from every producer (tray, listeners) to the Teams connection, in order,
+/// and the request to shut down
+#[derive(Clone)]
+pub struct CommandBus {
+    sender: mpsc::UnboundedSender<BusMessage>,
+}
+
+impl CommandBus {
+    pub fn new() -> (Self, CommandReceiver) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        (
+            Self { sender },
+            CommandReceiver {
+                receiver,
+                is_shutdown: false,
+            },
+        )
+    }
+
+    /// Sends the command, the receiver gets Teams' answer once it arrives or times out
+    pub fn request(&self, command: TeamsCommand) -> oneshot::Receiver<CommandOutcome> {
+        let (sender, receiver) = oneshot::channel();
+        let request = CommandRequest {
+            command,
+            queued_at: Instant::now(),
+            responder: sender,
+        };
+
+        // the receiver only goes away when the application closes, dropping the request
+        // tells the caller as much
+        let _ = self.sender.send(BusMessage::Command(request));
+        receiver
+    }
+
+    /// Parses and sends an action received by a listener (ex: over MQTT), the result resolves
+    /// with Teams' answer. The command is queued right away so that commands keep the order
+    /// they came in, whenever the result is awaited.
+    pub fn request_action(
+        &self,
+        action: &str,
+        parameter: &str,
+    ) -> impl Future<Output = CommandOutcome> + Send + 'static {
+        let request = TeamsCommand::parse(action, parameter).map(|command| self.request(command));
+        let action = action.to_string();
+
+        async move {
+            let outcome = match request {
+                Some(request) => request.await.unwrap_or_else(|_| {
+                    CommandOutcome::Failure("the application is closing".to_string())
+                }),
+                None => CommandOutcome::Failure(format!("unknown command '{}'", action)),
+            };
+
+            if outcome != CommandOutcome::Success {
+                warn!("Command '{}' was not applied: {}", action, outcome);
+            }
+
+            outcome
+        }
+    }
+
+    pub fn shutdown(&self) {
+        info!("Application close requested");
+        let _ = self.sender.send(BusMessage::Shutdown);
+    }
+}
+
+pub struct CommandReceiver {
+    receiver: mpsc::UnboundedReceiver<BusMessage>,
+    is_shutdown: bool,
+}
+
+impl CommandReceiver {
+    /// Waits for the next command, `None` once the application is shutting down
+    pub async fn recv(&mut self) -> Option<CommandRequest> {
+        if self.is_shutdown {
+            return None;
+        }
+
+        match self.receiver.recv().await {
+            Some(BusMessage::Command(request)) => Some(request),
+            Some(BusMessage::Shutdown) | None => {
+                self.is_shutdown = true;
+                None
+            }
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.is_shutdown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::teams_ws::commands::{CommandBus, CommandOutcome, Reaction, TeamsCommand};
+    use serde_json::Value;
+
+    #[test]
+    fn to_message_toggle_mute_will_match_teams_format() {
+        let message: Value =
+            serde_json::from_str(&TeamsCommand::ToggleMute.to_message(1, "2.0.0")).unwrap();
+
+        assert_eq!(message["requestId"], 1);
+        assert_eq!(message["apiVersion"], "2.0.0");
+        assert_eq!(message["action"], "toggle-mute");
+        assert!(message.get("parameters").is_none());
+    }
+
+    #[test]
+    fn to_message_send_reaction_will_include_reaction_type() {
+        let command = TeamsCommand::SendReaction(Reaction::Applause);
+        let message: Value = serde_json::from_str(&command.to_message(7, "2.0.0")).unwrap();
+
+        assert_eq!(message["action"], "send-reaction");
+        assert_eq!(message["parameters"]["type"], "applause");
+    }
+
+    #[test]
+    fn parse_action_names_will_round_trip() {
+        assert_eq!(
+            TeamsCommand::parse("Toggle-Video", ""),
+            Some(TeamsCommand::ToggleVideo)
+        );
+        assert_eq!(
+            TeamsCommand::parse("send-reaction", "wow"),
+            Some(TeamsCommand::SendReaction(Reaction::Wow))
+        );
+        assert_eq!(TeamsCommand::parse("send-reaction", "boo"), None);
+        assert_eq!(TeamsCommand::parse("unknown", ""), None);
+    }
+
+    #[tokio::test]
+    async fn recv_will_keep_order_and_stop_at_shutdown() {
+        let (bus, mut receiver) = CommandBus::new();
+        let _mute = bus.request(TeamsCommand::ToggleMute);
+        let _mute_again = bus.request(TeamsCommand::ToggleMute);
+        let _video = bus.request(TeamsCommand::ToggleVideo);
+        bus.shutdown();
+        let _ignored = bus.request(TeamsCommand::LeaveCall);
+
+        assert_eq!(
+            receiver.recv().await.unwrap().command,
+            TeamsCommand::ToggleMute
+        );
+        assert_eq!(
+            receiver.recv().await.unwrap().command,
+            TeamsCommand::ToggleMute
+        );
+        assert_eq!(
+            receiver.recv().await.unwrap().command,
+            TeamsCommand::ToggleVideo
+        );
+        assert!(receiver.recv().await.is_none());
+        assert!(receiver.is_shutdown());
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn request_action_unknown_will_fail_without_queueing() {
+        let (bus, mut receiver) = CommandBus::new();
+        let outcome = bus.request_action("toggle-everything", "");
+        bus.shutdown();
+
+        assert_eq!(
+            outcome.await,
+            CommandOutcome::Failure("unknown command 'toggle-everything'".to_string())
+        );
+        assert!(receiver.recv().await.is_none());
+    }
+}
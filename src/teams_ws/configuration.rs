@@ -1,31 +1,162 @@
+use crate::configuration::INI_FILE_NAME;
+use crate::utils::computer_name;
+use anyhow::anyhow;
 use ini::Ini;
 use log::info;
+use std::fs;
+use std::io::ErrorKind;
 
 pub const TEAMS: &str = "Teams";
 pub const TEAMS_URL: &str = "URL";
 pub const TEAMS_API_TOKEN: &str = "API Token";
+pub const TEAMS_MANUFACTURER: &str = "Manufacturer";
+pub const TEAMS_DEVICE: &str = "Device";
+pub const TEAMS_APP: &str = "App";
+pub const TEAMS_APP_VERSION: &str = "App Version";
+pub const TEAMS_PROTOCOL_VERSION: &str = "Protocol Version";
+pub const TEAMS_PING_INTERVAL: &str = "Ping Interval";
+pub const TEAMS_PING_TIMEOUT: &str = "Ping Timeout";
+pub const TEAMS_PING_INTERVAL_DEFAULT: u64 = 30;
+pub const TEAMS_PING_TIMEOUT_DEFAULT: u64 = 10;
+// the identity used before Device and App Version could be configured
+const TEAMS_DEVICE_LEGACY: &str = "MyPC";
+const TEAMS_APP_VERSION_LEGACY: &str = "1.0";
+// the New Teams protocol first, then the classic one
+const TEAMS_PROTOCOL_VERSION_DEFAULT: &str = "2.0.0, 1.0.0";
 
 pub struct TeamsConfiguration {
     pub url: String,
     pub api_token: String,
+    // shown by Teams in its list of paired apps, changing them requires pairing again
+    pub manufacturer: String,
+    // empty uses the computer name
+    pub device: String,
+    pub app: String,
+    // empty uses the version of the application
+    pub app_version: String,
+    // comma separated, in order of preference, see `protocol_versions`
+    pub protocol_version: String,
+    // in seconds, 0 disables the keepalive
+    pub ping_interval: u64,
+    pub ping_timeout: u64,
+}
+
+impl TeamsConfiguration {
+    /// Tokens paired before Device and App Version existed are tied to the old fixed identity,
+    /// it is kept for them (and saved) so that upgrading does not require pairing again
+    pub fn keep_legacy_identity(&mut self, has_device: bool, has_app_version: bool) {
+        if self.api_token.is_empty() {
+            return;
+        }
+
+        if !has_device {
+            self.device = TEAMS_DEVICE_LEGACY.to_string();
+        }
+
+        if !has_app_version {
+            self.app_version = TEAMS_APP_VERSION_LEGACY.to_string();
+        }
+    }
+
+    pub fn device(&self) -> String {
+        if !self.device.is_empty() {
+            return self.device.clone();
+        }
+
+        computer_name()
+    }
+
+    pub fn app_version(&self) -> String {
+        if !self.app_version.is_empty() {
+            return self.app_version.clone();
+        }
+
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+
+    /// The protocol versions to offer Teams, the next one is tried when Teams refuses a
+    /// connection and the first one it accepts is kept
+    pub fn protocol_versions(&self) -> Vec<String> {
+        let versions = split_versions(&self.protocol_version);
+
+        if versions.is_empty() {
+            split_versions(TEAMS_PROTOCOL_VERSION_DEFAULT)
+        } else {
+            versions
+        }
+    }
+}
+
+fn split_versions(protocol_version: &str) -> Vec<String> {
+    protocol_version
+        .split(',')
+        .map(|version| version.trim().to_string())
+        .filter(|version| !version.is_empty())
+        .collect()
 }
 
 pub fn create_teams_configuration() -> TeamsConfiguration {
     TeamsConfiguration {
         url: "ws://localhost:8124".to_string(),
         api_token: "".to_string(),
+        manufacturer: "HA-Integration".to_string(),
+        device: "".to_string(),
+        app: "teams-status-rs".to_string(),
+        app_version: "".to_string(),
+        protocol_version: TEAMS_PROTOCOL_VERSION_DEFAULT.to_string(),
+        ping_interval: TEAMS_PING_INTERVAL_DEFAULT,
+        ping_timeout: TEAMS_PING_TIMEOUT_DEFAULT,
     }
 }
 
-pub fn change_teams_configuration(section: &str, key: &str, value: &str) {
-    let mut i = Ini::load_from_file("conf.ini").unwrap_or_else(|err| {
-        info!(
-            "The file conf.ini could not be loaded but should already exist, exiting application: {}",
-            err.to_string()
-        );
-        panic!("{}", err.to_string());
-    });
+// The file is written next to the original and then moved over it, so that a crash or a full
+// disk never leaves a truncated configuration behind. A file that exists but cannot be read is
+// left alone, writing it would lose everything but the changed key
+pub fn change_teams_configuration(section: &str, key: &str, value: &str) -> anyhow::Result<()> {
+    let mut i = match Ini::load_from_file(INI_FILE_NAME) {
+        Ok(i) => i,
+        Err(ini::Error::Io(err)) if err.kind() == ErrorKind::NotFound => {
+            info!("The file conf.ini does not exist, a new one will be created");
+            Ini::new()
+        }
+        Err(err) => return Err(anyhow!("Unable to load conf.ini: {}", err)),
+    };
 
     i.with_section(Some(section)).set(key, value);
-    i.write_to_file("conf.ini").unwrap();
+    let temp_file_name = format!("{}.tmp", INI_FILE_NAME);
+    i.write_to_file(&temp_file_name)?;
+    fs::rename(&temp_file_name, INI_FILE_NAME)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::teams_ws::configuration::create_teams_configuration;
+
+    #[test]
+    fn keep_legacy_identity_paired_without_keys_will_use_old_identity() {
+        let mut conf = create_teams_configuration();
+        conf.api_token = "token".to_string();
+        conf.keep_legacy_identity(false, false);
+
+        assert_eq!(conf.device(), "MyPC");
+        assert_eq!(conf.app_version(), "1.0");
+    }
+
+    #[test]
+    fn protocol_versions_without_version_will_split_default() {
+        let mut conf = create_teams_configuration();
+        conf.protocol_version = ",".to_string();
+
+        assert_eq!(conf.protocol_versions(), vec!["2.0.0", "1.0.0"]);
+    }
+
+    #[test]
+    fn keep_legacy_identity_unpaired_will_use_defaults() {
+        let mut conf = create_teams_configuration();
+        conf.keep_legacy_identity(false, false);
+
+        assert!(conf.device.is_empty());
+        assert!(conf.app_version.is_empty());
+    }
 }
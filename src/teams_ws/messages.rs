@@ -0,0 +1,96 @@
+use crate::teams_ws::states::MeetingPermissions;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+/// Any message Teams sends over the websocket, only the fields we use are modeled
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TeamsMessage {
+    pub meeting_update: Option<MeetingUpdate>,
+    pub token_refresh: Option<String>,
+    pub request_id: Option<u32>,
+    pub response: Option<String>,
+    pub error_msg: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MeetingUpdate {
+    #[serde(default)]
+    pub meeting_state: MeetingState,
+    pub meeting_permissions: Option<MeetingPermissions>,
+}
+
+/// Every flag is `None` when Teams did not send it (or sent something that is not a boolean)
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MeetingState {
+    #[serde(default, deserialize_with = "flag")]
+    pub is_muted: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub is_video_on: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub is_hand_raised: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub is_in_meeting: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub is_recording_on: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub is_background_blurred: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub is_sharing: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub has_unread_messages: Option<bool>,
+}
+
+// Teams has been seen sending null for some fields, anything that is not a boolean is unknown
+pub fn flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<bool>, D::Error> {
+    Ok(Value::deserialize(deserializer)?.as_bool())
+}
+
+pub fn parse_message(json: &str) -> serde_json::Result<TeamsMessage> {
+    serde_json::from_str(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::teams_ws::messages::parse_message;
+
+    #[test]
+    fn parse_message_meeting_update_will_keep_missing_fields_unknown() {
+        let message = parse_message(
+            r#"{"meetingUpdate":{"meetingState":{"isMuted":true,"isVideoOn":false,"isSharing":null}}}"#,
+        )
+        .unwrap();
+        let meeting_update = message.meeting_update.unwrap();
+        let meeting_state = meeting_update.meeting_state;
+
+        assert_eq!(meeting_state.is_muted, Some(true));
+        assert_eq!(meeting_state.is_video_on, Some(false));
+        assert_eq!(meeting_state.is_sharing, None);
+        assert_eq!(meeting_state.is_in_meeting, None);
+        assert!(meeting_update.meeting_permissions.is_none());
+    }
+
+    #[test]
+    fn parse_message_permissions_will_be_read() {
+        let message = parse_message(
+            r#"{"meetingUpdate":{"meetingPermissions":{"canToggleMute":true,"canPair":false}}}"#,
+        )
+        .unwrap();
+        let permissions = message.meeting_update.unwrap().meeting_permissions.unwrap();
+
+        assert_eq!(permissions.can_toggle_mute, Some(true));
+        assert_eq!(permissions.can_pair, Some(false));
+        assert_eq!(permissions.can_leave, None);
+    }
+
+    #[test]
+    fn parse_message_response_will_be_read() {
+        let message = parse_message(r#"{"requestId":2,"response":"Success"}"#).unwrap();
+
+        assert_eq!(message.request_id, Some(2));
+        assert_eq!(message.response.as_deref(), Some("Success"));
+        assert!(message.meeting_update.is_none());
+    }
+}
@@ -1,3 +1,7 @@
 pub mod api;
+pub mod commands;
 pub mod configuration;
+pub mod messages;
+pub mod pairing;
+pub mod protocol;
 pub mod states;
@@ -0,0 +1,177 @@
+use crate::teams_ws::configuration::{change_teams_configuration, TEAMS, TEAMS_API_TOKEN};
+use crate::utils::encrypt;
+use log::{error, info, warn};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PairingState {
+    Paired,
+    Unpaired,
+    WaitingForMeeting,
+    WaitingForApproval,
+    TokenPossiblyRejected,
+}
+
+impl fmt::Display for PairingState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PairingState::Paired => write!(f, "paired"),
+            PairingState::Unpaired => write!(f, "not paired"),
+            PairingState::WaitingForMeeting => write!(f, "join a meeting to pair"),
+            PairingState::WaitingForApproval => write!(f, "allow the application in Teams"),
+            PairingState::TokenPossiblyRejected => {
+                write!(f, "token possibly rejected, pair again")
+            }
+        }
+    }
+}
+
+/// The Teams token and the pairing progress, shared between the Teams connection, the tray and
+/// the command line. Pairing is requested here and carried out by the Teams connection once a
+/// meeting allows it.
+#[derive(Clone)]
+pub struct Pairing {
+    token: Arc<RwLock<String>>,
+    state: Arc<RwLock<PairingState>>,
+    requested: Arc<AtomicBool>,
+}
+
+impl Default for Pairing {
+    fn default() -> Self {
+        Self {
+            token: Arc::new(RwLock::new(String::new())),
+            state: Arc::new(RwLock::new(PairingState::Unpaired)),
+            requested: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl Pairing {
+    /// Uses the token from the configuration, a pairing requested beforehand is kept
+    pub fn initialize(&self, api_token: &str) {
+        *self.token.write().unwrap() = api_token.to_string();
+
+        if !self.is_requested() {
+            self.set_state(if api_token.is_empty() {
+                PairingState::Unpaired
+            } else {
+                PairingState::Paired
+            });
+        }
+    }
+
+    pub fn token(&self) -> String {
+        self.token.read().unwrap().clone()
+    }
+
+    pub fn state(&self) -> PairingState {
+        *self.state.read().unwrap()
+    }
+
+    pub fn set_state(&self, state: PairingState) {
+        let mut current = self.state.write().unwrap();
+
+        if *current != state {
+            info!("Teams pairing: {}", state);
+            *current = state;
+        }
+    }
+
+    pub fn request(&self) {
+        info!("Pairing with Teams requested");
+        // Teams would keep dropping a rejected token, so the pairing connection goes without it.
+        // The saved one is only replaced once Teams sends a new token.
+        if self.state() == PairingState::TokenPossiblyRejected {
+            self.token.write().unwrap().clear();
+        }
+
+        self.requested.store(true, Ordering::Relaxed);
+        self.set_state(PairingState::WaitingForMeeting);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    /// Returns true once per request, when it is time to send the pairing command
+    pub fn take_request(&self) -> bool {
+        self.requested.swap(false, Ordering::Relaxed)
+    }
+
+    /// Teams sends a new token once the user allowed the application
+    pub fn token_refreshed(&self, token: &str) {
+        *self.token.write().unwrap() = token.to_string();
+        self.requested.store(false, Ordering::Relaxed);
+        self.save_token(token);
+        self.set_state(PairingState::Paired);
+    }
+
+    pub fn pairing_failed(&self, reason: &str) {
+        warn!("Pairing with Teams failed: {}", reason);
+        self.set_state(if self.token().is_empty() {
+            PairingState::Unpaired
+        } else {
+            PairingState::Paired
+        });
+    }
+
+    /// Teams keeps dropping the connection without a word, which it does for a rejected token but
+    /// also while it starts or updates. The token is kept, the user decides whether to pair again.
+    pub fn token_possibly_rejected(&self) {
+        warn!("Teams may have rejected the stored token, pair again if it keeps happening");
+        self.set_state(PairingState::TokenPossiblyRejected);
+    }
+
+    /// Teams talked to us, so the token is fine after all
+    pub fn connection_accepted(&self) {
+        if self.state() == PairingState::TokenPossiblyRejected {
+            self.set_state(PairingState::Paired);
+        }
+    }
+
+    fn save_token(&self, token: &str) {
+        if let Err(error) = change_teams_configuration(TEAMS, TEAMS_API_TOKEN, &encrypt(token)) {
+            error!("Unable to save the Teams token: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::teams_ws::pairing::{Pairing, PairingState};
+
+    #[test]
+    fn initialize_without_token_will_be_unpaired() {
+        let pairing = Pairing::default();
+        pairing.initialize("");
+
+        assert_eq!(pairing.state(), PairingState::Unpaired);
+    }
+
+    #[test]
+    fn token_possibly_rejected_will_keep_token() {
+        let pairing = Pairing::default();
+        pairing.initialize("token");
+        pairing.token_possibly_rejected();
+
+        assert_eq!(pairing.state(), PairingState::TokenPossiblyRejected);
+        assert_eq!(pairing.token(), "token");
+
+        pairing.connection_accepted();
+        assert_eq!(pairing.state(), PairingState::Paired);
+    }
+
+    #[test]
+    fn initialize_after_request_will_keep_waiting_for_meeting() {
+        let pairing = Pairing::default();
+        pairing.request();
+        pairing.initialize("token");
+
+        assert_eq!(pairing.state(), PairingState::WaitingForMeeting);
+        assert_eq!(pairing.token(), "token");
+        assert!(pairing.take_request());
+        assert!(!pairing.take_request());
+    }
+}
@@ -0,0 +1,33 @@
+pub mod v1;
+pub mod v2;
+
+use crate::teams_ws::configuration::TeamsConfiguration;
+use crate::traits::TeamsProtocol;
+
+/// How the application introduces itself to Teams
+pub struct ClientIdentity {
+    pub manufacturer: String,
+    pub device: String,
+    pub app: String,
+    pub app_version: String,
+}
+
+impl ClientIdentity {
+    pub fn new(conf: &TeamsConfiguration) -> Self {
+        Self {
+            manufacturer: conf.manufacturer.clone(),
+            device: conf.device(),
+            app: conf.app.clone(),
+            app_version: conf.app_version(),
+        }
+    }
+}
+
+/// Versions 1.x are spoken by the classic Teams client, anything else by the New Teams
+pub fn create_protocol(version: &str) -> Box<dyn TeamsProtocol> {
+    if version.starts_with("1.") {
+        Box::new(v1::ProtocolV1::new(version))
+    } else {
+        Box::new(v2::ProtocolV2::new(version))
+    }
+}
@@ -0,0 +1,228 @@
+use crate::teams_ws::commands::{Reaction, TeamsCommand};
+use crate::teams_ws::messages::{flag, MeetingState, MeetingUpdate, TeamsMessage};
+use crate::teams_ws::protocol::ClientIdentity;
+use crate::teams_ws::states::MeetingPermissions;
+use crate::traits::TeamsProtocol;
+use anyhow::anyhow;
+use serde::Deserialize;
+use serde_json::json;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The protocol of the classic Teams client, the token is copied from the Teams settings, the
+/// camera is reported as `isCameraOn` and commands are never answered
+pub struct ProtocolV1 {
+    version: String,
+}
+
+impl ProtocolV1 {
+    pub fn new(version: &str) -> Self {
+        Self {
+            version: version.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MessageV1 {
+    meeting_update: Option<MeetingUpdateV1>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MeetingUpdateV1 {
+    #[serde(default)]
+    meeting_state: MeetingStateV1,
+    meeting_permissions: Option<MeetingPermissions>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MeetingStateV1 {
+    #[serde(default, deserialize_with = "flag")]
+    is_muted: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    is_camera_on: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    is_hand_raised: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    is_in_meeting: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    is_recording_on: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    is_background_blurred: Option<bool>,
+}
+
+// (service, action) as expected by the classic client
+fn service_and_action(command: TeamsCommand) -> Option<(&'static str, String)> {
+    let service_and_action = match command {
+        TeamsCommand::ToggleMute => ("toggle-mute", "toggle-mute".to_string()),
+        TeamsCommand::ToggleVideo => ("toggle-video", "toggle-video".to_string()),
+        TeamsCommand::ToggleHand => ("raise-hand", "toggle-hand".to_string()),
+        TeamsCommand::ToggleBackgroundBlur => {
+            ("background-blur", "toggle-background-blur".to_string())
+        }
+        TeamsCommand::LeaveCall => ("call", "leave-call".to_string()),
+        TeamsCommand::SendReaction(reaction) => {
+            let reaction = match reaction {
+                Reaction::Laugh => "laughter",
+                _ => reaction.as_str(),
+            };
+            ("call", format!("react-{}", reaction))
+        }
+        TeamsCommand::QueryState => ("query-meeting-state", "query-meeting-state".to_string()),
+        TeamsCommand::StopSharing | TeamsCommand::Pair => return None,
+    };
+
+    Some(service_and_action)
+}
+
+impl TeamsProtocol for ProtocolV1 {
+    fn version(&self) -> &str {
+        &self.version
+    }
+
+    fn connection_url(
+        &self,
+        base_url: &str,
+        identity: &ClientIdentity,
+        api_token: &str,
+    ) -> anyhow::Result<url::Url> {
+        if api_token.is_empty() {
+            return Err(anyhow!(
+                "The classic Teams client requires the API Token from its privacy settings"
+            ));
+        }
+
+        Ok(url::Url::parse_with_params(
+            base_url,
+            [
+                ("token", api_token),
+                ("protocol-version", self.version.as_str()),
+                ("manufacturer", &identity.manufacturer),
+                ("device", &identity.device),
+                ("app", &identity.app),
+                ("app-version", &identity.app_version),
+            ],
+        )?)
+    }
+
+    fn parse_message(&self, json: &str) -> serde_json::Result<TeamsMessage> {
+        let message: MessageV1 = serde_json::from_str(json)?;
+
+        Ok(TeamsMessage {
+            meeting_update: message.meeting_update.map(|meeting_update| {
+                let meeting_state = meeting_update.meeting_state;
+
+                MeetingUpdate {
+                    meeting_state: MeetingState {
+                        is_muted: meeting_state.is_muted,
+                        is_video_on: meeting_state.is_camera_on,
+                        is_hand_raised: meeting_state.is_hand_raised,
+                        is_in_meeting: meeting_state.is_in_meeting,
+                        is_recording_on: meeting_state.is_recording_on,
+                        is_background_blurred: meeting_state.is_background_blurred,
+                        // not reported by the classic client
+                        is_sharing: None,
+                        has_unread_messages: None,
+                    },
+                    meeting_permissions: meeting_update.meeting_permissions,
+                }
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn supports(&self, command: TeamsCommand) -> bool {
+        service_and_action(command).is_some()
+    }
+
+    fn command_message(
+        &self,
+        command: TeamsCommand,
+        request_id: u32,
+        identity: &ClientIdentity,
+    ) -> String {
+        let (service, action) = service_and_action(command).unwrap_or_default();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        json!({
+            "apiVersion": self.version,
+            "service": service,
+            "action": action,
+            "manufacturer": identity.manufacturer,
+            "device": identity.device,
+            "timestamp": timestamp,
+            "requestId": request_id,
+        })
+        .to_string()
+    }
+
+    fn confirms_commands(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::teams_ws::commands::{Reaction, TeamsCommand};
+    use crate::teams_ws::protocol::v1::ProtocolV1;
+    use crate::teams_ws::protocol::ClientIdentity;
+    use crate::traits::TeamsProtocol;
+    use serde_json::Value;
+
+    fn identity() -> ClientIdentity {
+        ClientIdentity {
+            manufacturer: "HA-Integration".to_string(),
+            device: "PC".to_string(),
+            app: "teams-status-rs".to_string(),
+            app_version: "1.0".to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_message_camera_will_map_to_video() {
+        let message = ProtocolV1::new("1.0.0")
+            .parse_message(
+                r#"{"apiVersion":"1.0.0","meetingUpdate":{"meetingState":{"isMuted":true,"isCameraOn":true},"meetingPermissions":{"canToggleMute":true}}}"#,
+            )
+            .unwrap();
+        let meeting_update = message.meeting_update.unwrap();
+
+        assert_eq!(meeting_update.meeting_state.is_muted, Some(true));
+        assert_eq!(meeting_update.meeting_state.is_video_on, Some(true));
+        assert_eq!(meeting_update.meeting_state.is_sharing, None);
+        assert_eq!(
+            meeting_update.meeting_permissions.unwrap().can_toggle_mute,
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn command_message_will_use_classic_service_and_action() {
+        let protocol = ProtocolV1::new("1.0.0");
+        let command = TeamsCommand::SendReaction(Reaction::Laugh);
+        let message: Value =
+            serde_json::from_str(&protocol.command_message(command, 3, &identity())).unwrap();
+
+        assert_eq!(message["apiVersion"], "1.0.0");
+        assert_eq!(message["service"], "call");
+        assert_eq!(message["action"], "react-laughter");
+        assert!(!protocol.supports(TeamsCommand::StopSharing));
+    }
+
+    #[test]
+    fn connection_url_without_token_will_fail() {
+        let protocol = ProtocolV1::new("1.0.0");
+
+        assert!(protocol
+            .connection_url("ws://localhost:8124", &identity(), "")
+            .is_err());
+        assert!(protocol
+            .connection_url("ws://localhost:8124", &identity(), "token")
+            .is_ok());
+    }
+}
@@ -0,0 +1,66 @@
+use crate::teams_ws::commands::TeamsCommand;
+use crate::teams_ws::messages::{parse_message, TeamsMessage};
+use crate::teams_ws::protocol::ClientIdentity;
+use crate::traits::TeamsProtocol;
+
+/// The protocol of the New Teams client, it pairs through a prompt in Teams and answers every
+/// command with its request id
+pub struct ProtocolV2 {
+    version: String,
+}
+
+impl ProtocolV2 {
+    pub fn new(version: &str) -> Self {
+        Self {
+            version: version.to_string(),
+        }
+    }
+}
+
+impl TeamsProtocol for ProtocolV2 {
+    fn version(&self) -> &str {
+        &self.version
+    }
+
+    fn connection_url(
+        &self,
+        base_url: &str,
+        identity: &ClientIdentity,
+        api_token: &str,
+    ) -> anyhow::Result<url::Url> {
+        let mut params = vec![];
+        if !api_token.is_empty() {
+            params.push(("token", api_token));
+        }
+        params.extend([
+            ("protocol-version", self.version.as_str()),
+            ("manufacturer", &identity.manufacturer),
+            ("device", &identity.device),
+            ("app", &identity.app),
+            ("app-version", &identity.app_version),
+        ]);
+
+        Ok(url::Url::parse_with_params(base_url, params)?)
+    }
+
+    fn parse_message(&self, json: &str) -> serde_json::Result<TeamsMessage> {
+        parse_message(json)
+    }
+
+    fn supports(&self, _command: TeamsCommand) -> bool {
+        true
+    }
+
+    fn command_message(
+        &self,
+        command: TeamsCommand,
+        request_id: u32,
+        _identity: &ClientIdentity,
+    ) -> String {
+        command.to_message(request_id, &self.version)
+    }
+
+    fn confirms_commands(&self) -> bool {
+        true
+    }
+}
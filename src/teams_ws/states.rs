@@ -1,20 +1,341 @@
-use std::sync::atomic::AtomicBool;
-
-pub struct TeamsStates {
-    pub is_muted: AtomicBool,
-    pub prev_is_muted: AtomicBool,
-    pub is_video_on: AtomicBool,
-    pub prev_is_video_on: AtomicBool,
-    pub is_hand_raised: AtomicBool,
-    pub prev_is_hand_raised: AtomicBool,
-    pub is_in_meeting: AtomicBool,
-    pub prev_is_in_meeting: AtomicBool,
-    pub is_recording_on: AtomicBool,
-    pub prev_is_recording_on: AtomicBool,
-    pub is_background_blurred: AtomicBool,
-    pub prev_is_background_blurred: AtomicBool,
-    pub is_sharing: AtomicBool,
-    pub prev_is_sharing: AtomicBool,
-    pub has_unread_messages: AtomicBool,
-    pub prev_has_unread_messages: AtomicBool,
+use crate::teams_ws::commands::TeamsCommand;
+use crate::teams_ws::messages::{flag, MeetingUpdate};
+use log::info;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+/// Every field of a snapshot, used to tell what changed and when
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TeamsField {
+    Available,
+    Muted,
+    VideoOn,
+    HandRaised,
+    InMeeting,
+    RecordingOn,
+    BackgroundBlurred,
+    Sharing,
+    UnreadMessages,
+    Permissions,
+}
+
+impl TeamsField {
+    pub const ALL: [TeamsField; 10] = [
+        TeamsField::Available,
+        TeamsField::Muted,
+        TeamsField::VideoOn,
+        TeamsField::HandRaised,
+        TeamsField::InMeeting,
+        TeamsField::RecordingOn,
+        TeamsField::BackgroundBlurred,
+        TeamsField::Sharing,
+        TeamsField::UnreadMessages,
+        TeamsField::Permissions,
+    ];
+
+    // the name Teams uses
+    pub fn name(self) -> &'static str {
+        match self {
+            TeamsField::Available => "isAvailable",
+            TeamsField::Muted => "isMuted",
+            TeamsField::VideoOn => "isVideoOn",
+            TeamsField::HandRaised => "isHandRaised",
+            TeamsField::InMeeting => "isInMeeting",
+            TeamsField::RecordingOn => "isRecordingOn",
+            TeamsField::BackgroundBlurred => "isBackgroundBlurred",
+            TeamsField::Sharing => "isSharing",
+            TeamsField::UnreadMessages => "hasUnreadMessages",
+            TeamsField::Permissions => "meetingPermissions",
+        }
+    }
+
+    // the name of the snapshot field, used in the configuration and in Home Assistant events
+    pub fn key(self) -> &'static str {
+        match self {
+            TeamsField::Available => "is_available",
+            TeamsField::Muted => "is_muted",
+            TeamsField::VideoOn => "is_video_on",
+            TeamsField::HandRaised => "is_hand_raised",
+            TeamsField::InMeeting => "is_in_meeting",
+            TeamsField::RecordingOn => "is_recording_on",
+            TeamsField::BackgroundBlurred => "is_background_blurred",
+            TeamsField::Sharing => "is_sharing",
+            TeamsField::UnreadMessages => "has_unread_messages",
+            TeamsField::Permissions => "permissions",
+        }
+    }
+
+    pub fn parse(key: &str) -> Option<Self> {
+        TeamsField::ALL
+            .into_iter()
+            .find(|field| field.key().eq_ignore_ascii_case(key.trim()))
+    }
+}
+
+/// Teams' state at one point in time, a new snapshot is published on every change. Consumers
+/// keep the last snapshot they handled and compare it with the new one using `diff`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TeamsSnapshot {
+    // false until Teams sends its first update, and once the connection is lost
+    pub is_available: bool,
+    // every flag is None when Teams did not send it
+    pub is_muted: Option<bool>,
+    pub is_video_on: Option<bool>,
+    pub is_hand_raised: Option<bool>,
+    pub is_in_meeting: Option<bool>,
+    pub is_recording_on: Option<bool>,
+    pub is_background_blurred: Option<bool>,
+    pub is_sharing: Option<bool>,
+    pub has_unread_messages: Option<bool>,
+    // None until Teams sends its first meetingPermissions block
+    pub permissions: Option<MeetingPermissions>,
+    changed_at: BTreeMap<TeamsField, SystemTime>,
+}
+
+impl TeamsSnapshot {
+    /// The value of a flag field, `None` for the other fields
+    pub fn flag(&self, field: TeamsField) -> Option<bool> {
+        match field {
+            TeamsField::Available => Some(self.is_available),
+            TeamsField::Muted => self.is_muted,
+            TeamsField::VideoOn => self.is_video_on,
+            TeamsField::HandRaised => self.is_hand_raised,
+            TeamsField::InMeeting => self.is_in_meeting,
+            TeamsField::RecordingOn => self.is_recording_on,
+            TeamsField::BackgroundBlurred => self.is_background_blurred,
+            TeamsField::Sharing => self.is_sharing,
+            TeamsField::UnreadMessages => self.has_unread_messages,
+            TeamsField::Permissions => None,
+        }
+    }
+
+    /// When the field last changed, `None` if it never did
+    pub fn changed_at(&self, field: TeamsField) -> Option<SystemTime> {
+        self.changed_at.get(&field).copied()
+    }
+
+    /// The fields that differ from `previous`, all of them when there is nothing to compare to
+    pub fn diff(&self, previous: Option<&TeamsSnapshot>) -> Vec<TeamsField> {
+        TeamsField::ALL
+            .into_iter()
+            .filter(|field| match previous {
+                None => true,
+                Some(previous) if *field == TeamsField::Permissions => {
+                    previous.permissions != self.permissions
+                }
+                Some(previous) => previous.flag(*field) != self.flag(*field),
+            })
+            .collect()
+    }
+
+    /// Applies an update from Teams, returns whether anything changed
+    pub fn apply(&mut self, meeting_update: MeetingUpdate) -> bool {
+        let meeting_state = meeting_update.meeting_state;
+        let mut has_changed = self.set_available(true);
+
+        for (field, value) in [
+            (TeamsField::Muted, meeting_state.is_muted),
+            (TeamsField::VideoOn, meeting_state.is_video_on),
+            (TeamsField::HandRaised, meeting_state.is_hand_raised),
+            (TeamsField::InMeeting, meeting_state.is_in_meeting),
+            (TeamsField::RecordingOn, meeting_state.is_recording_on),
+            (
+                TeamsField::BackgroundBlurred,
+                meeting_state.is_background_blurred,
+            ),
+            (TeamsField::Sharing, meeting_state.is_sharing),
+            (
+                TeamsField::UnreadMessages,
+                meeting_state.has_unread_messages,
+            ),
+        ] {
+            has_changed |= self.set_flag(field, value);
+        }
+
+        // permissions are only sent when they change
+        if let Some(permissions) = meeting_update.meeting_permissions {
+            if self.permissions.as_ref() != Some(&permissions) {
+                self.permissions = Some(permissions);
+                self.changed_at
+                    .insert(TeamsField::Permissions, SystemTime::now());
+                has_changed = true;
+            }
+        }
+
+        has_changed
+    }
+
+    /// Returns whether Teams was available until now
+    pub fn set_unavailable(&mut self) -> bool {
+        self.set_available(false)
+    }
+
+    fn set_available(&mut self, is_available: bool) -> bool {
+        if self.is_available == is_available {
+            return false;
+        }
+
+        self.is_available = is_available;
+        self.changed_at
+            .insert(TeamsField::Available, SystemTime::now());
+        true
+    }
+
+    fn set_flag(&mut self, field: TeamsField, value: Option<bool>) -> bool {
+        let current = match field {
+            TeamsField::Muted => &mut self.is_muted,
+            TeamsField::VideoOn => &mut self.is_video_on,
+            TeamsField::HandRaised => &mut self.is_hand_raised,
+            TeamsField::InMeeting => &mut self.is_in_meeting,
+            TeamsField::RecordingOn => &mut self.is_recording_on,
+            TeamsField::BackgroundBlurred => &mut self.is_background_blurred,
+            TeamsField::Sharing => &mut self.is_sharing,
+            TeamsField::UnreadMessages => &mut self.has_unread_messages,
+            TeamsField::Available | TeamsField::Permissions => return false,
+        };
+
+        if *current == value {
+            return false;
+        }
+
+        // the classic client never sends some of them, so this is only logged once
+        if value.is_none() {
+            info!("Teams did not send {}, it is now unknown", field.name());
+        }
+
+        *current = value;
+        self.changed_at.insert(field, SystemTime::now());
+        true
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MeetingPermissions {
+    #[serde(default, deserialize_with = "flag")]
+    pub can_toggle_mute: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub can_toggle_video: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub can_toggle_hand: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub can_toggle_blur: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub can_leave: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub can_react: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub can_toggle_share_tray: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub can_toggle_chat: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub can_stop_sharing: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub can_pair: Option<bool>,
+}
+
+impl MeetingPermissions {
+    /// Whether Teams would accept the command, while pairing is possible every command is let
+    /// through as that is what triggers the pairing prompt in Teams. Unknown permissions are
+    /// let through as well, Teams will answer with a failure if needed.
+    pub fn allows(&self, command: TeamsCommand) -> bool {
+        let permission = match command {
+            TeamsCommand::ToggleMute => self.can_toggle_mute,
+            TeamsCommand::ToggleVideo => self.can_toggle_video,
+            TeamsCommand::ToggleHand => self.can_toggle_hand,
+            TeamsCommand::ToggleBackgroundBlur => self.can_toggle_blur,
+            TeamsCommand::LeaveCall => self.can_leave,
+            TeamsCommand::StopSharing => self.can_stop_sharing,
+            TeamsCommand::SendReaction(_) => self.can_react,
+            TeamsCommand::QueryState => None,
+            TeamsCommand::Pair => self.can_pair,
+        };
+
+        self.can_pair == Some(true) || permission != Some(false)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "can_toggle_mute": self.can_toggle_mute,
+            "can_toggle_video": self.can_toggle_video,
+            "can_toggle_hand": self.can_toggle_hand,
+            "can_toggle_blur": self.can_toggle_blur,
+            "can_leave": self.can_leave,
+            "can_react": self.can_react,
+            "can_toggle_share_tray": self.can_toggle_share_tray,
+            "can_toggle_chat": self.can_toggle_chat,
+            "can_stop_sharing": self.can_stop_sharing,
+            "can_pair": self.can_pair,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::teams_ws::messages::parse_message;
+    use crate::teams_ws::states::{TeamsField, TeamsSnapshot};
+
+    fn apply(snapshot: &mut TeamsSnapshot, json: &str) -> bool {
+        let meeting_update = parse_message(json).unwrap().meeting_update.unwrap();
+        snapshot.apply(meeting_update)
+    }
+
+    #[test]
+    fn apply_changed_flag_will_be_in_diff_with_timestamp() {
+        let mut snapshot = TeamsSnapshot::default();
+        apply(
+            &mut snapshot,
+            r#"{"meetingUpdate":{"meetingState":{"isMuted":false,"isVideoOn":true}}}"#,
+        );
+        let previous = snapshot.clone();
+
+        assert!(apply(
+            &mut snapshot,
+            r#"{"meetingUpdate":{"meetingState":{"isMuted":true,"isVideoOn":true}}}"#,
+        ));
+        assert_eq!(snapshot.diff(Some(&previous)), vec![TeamsField::Muted]);
+        assert!(snapshot.changed_at(TeamsField::Muted) >= previous.changed_at(TeamsField::Muted));
+        assert_eq!(
+            snapshot.changed_at(TeamsField::VideoOn),
+            previous.changed_at(TeamsField::VideoOn)
+        );
+    }
+
+    #[test]
+    fn apply_same_update_will_not_change() {
+        let mut snapshot = TeamsSnapshot::default();
+        let json = r#"{"meetingUpdate":{"meetingState":{"isMuted":true}}}"#;
+
+        assert!(apply(&mut snapshot, json));
+        assert!(!apply(&mut snapshot, json));
+    }
+
+    #[test]
+    fn diff_without_previous_will_contain_every_field() {
+        let snapshot = TeamsSnapshot::default();
+
+        assert_eq!(snapshot.diff(None), TeamsField::ALL.to_vec());
+    }
+
+    #[test]
+    fn parse_keys_will_round_trip() {
+        for field in TeamsField::ALL {
+            assert_eq!(TeamsField::parse(field.key()), Some(field));
+        }
+
+        assert_eq!(TeamsField::parse(" Is_Muted "), Some(TeamsField::Muted));
+        assert_eq!(TeamsField::parse("muted"), None);
+    }
+
+    #[test]
+    fn set_unavailable_will_only_change_once() {
+        let mut snapshot = TeamsSnapshot::default();
+        apply(&mut snapshot, r#"{"meetingUpdate":{}}"#);
+
+        assert!(snapshot.is_available);
+        assert!(snapshot.set_unavailable());
+        assert!(!snapshot.set_unavailable());
+    }
 }
@@ -1,4 +1,7 @@
-use crate::teams_ws::states::TeamsStates;
+use crate::teams_ws::commands::TeamsCommand;
+use crate::teams_ws::messages::TeamsMessage;
+use crate::teams_ws::protocol::ClientIdentity;
+use crate::teams_ws::states::TeamsSnapshot;
 use async_trait::async_trait;
 
 pub trait StopController {
@@ -6,11 +9,47 @@ pub trait StopController {
 }
 
 #[async_trait]
-pub trait Listener {
+pub trait Listener: Send {
+    // `previous` is the snapshot the listener was last notified of, None when everything needs
+    // to be sent (ex: after connecting or failing)
     async fn notify_changed(
         &mut self,
-        teams_states: &TeamsStates,
-        force_update: bool,
+        snapshot: &TeamsSnapshot,
+        previous: Option<&TeamsSnapshot>,
     ) -> anyhow::Result<()>;
+    // called when the connection to Teams is lost, listeners should not keep showing stale states
+    async fn notify_unavailable(&mut self) -> anyhow::Result<()>;
+    // resolves when the listener needs every state again (ex: Home Assistant restarted and lost
+    // them), it is dropped whenever there is something else to do, so it must be safe to cancel
+    async fn wait_for_resync(&mut self) {
+        futures_util::future::pending::<()>().await
+    }
+    // called once when the application closes
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
     fn reconnect(&mut self);
 }
+
+/// A version of the local Teams websocket API, each one maps its messages and actions onto the
+/// same states and commands
+pub trait TeamsProtocol: Send + Sync {
+    fn version(&self) -> &str;
+    fn connection_url(
+        &self,
+        base_url: &str,
+        identity: &ClientIdentity,
+        api_token: &str,
+    ) -> anyhow::Result<url::Url>;
+    fn parse_message(&self, json: &str) -> serde_json::Result<TeamsMessage>;
+    fn supports(&self, command: TeamsCommand) -> bool;
+    // only called for supported commands
+    fn command_message(
+        &self,
+        command: TeamsCommand,
+        request_id: u32,
+        identity: &ClientIdentity,
+    ) -> String;
+    // whether Teams answers every command with its request id
+    fn confirms_commands(&self) -> bool;
+}
@@ -1,3 +1,4 @@
+use crate::teams_ws::commands::{CommandOutcome, Reaction, TeamsCommand};
 use crate::traits::StopController;
 use auto_launch::AutoLaunch;
 use image::GenericImageView;
@@ -5,12 +6,18 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 use std::sync::{Arc, RwLock};
 use tray_icon::{
-    menu::{Menu, MenuId, MenuItem},
+    menu::{Menu, MenuId, MenuItem, PredefinedMenuItem, Submenu},
     Icon, TrayIcon, TrayIconBuilder,
 };
 
+const TOOLTIP: &str = "Teams Status";
+// Windows cuts tooltips at 128 UTF-16 units, including the terminating null
+const TOOLTIP_MAX_LENGTH: usize = 127;
+
 pub struct TrayWindows {
     tray: TrayIcon,
+    connection_summary: String,
+    command_error: Option<String>,
     auto_launch: RwLock<AutoLaunch>,
     needs_recreation: Arc<AtomicBool>,
 }
@@ -20,7 +27,7 @@ static GLOBAL_AUTO_LAUNCH: Mutex<Option<Arc<RwLock<AutoLaunch>>>> = Mutex::new(N
 static GLOBAL_TRAY_RECREATION_FLAG: Mutex<Option<Arc<AtomicBool>>> = Mutex::new(None);
 
 impl TrayWindows {
-    pub fn new(_is_running: Arc<AtomicBool>, _toggle_mute: Arc<AtomicBool>) -> Self {
+    fn new() -> Self {
         // Setup auto-launch
         let exe_path = std::env::current_exe().unwrap();
         let exe_str = exe_path.to_str().unwrap();
@@ -47,6 +54,8 @@ impl TrayWindows {
 
         TrayWindows {
             tray: tray_icon,
+            connection_summary: String::new(),
+            command_error: None,
             auto_launch: Arc::<std::sync::RwLock<AutoLaunch>>::try_unwrap(auto_launch_arc)
                 .unwrap_or_else(|arc| RwLock::from((*arc).read().unwrap().clone())),
             needs_recreation: recreation_flag,
@@ -55,8 +64,29 @@ impl TrayWindows {
 
     fn create_tray_icon(auto_launch: &AutoLaunch) -> TrayIcon {
         let menu = Menu::new();
-        let toggle_mute_item =
-            MenuItem::with_id(MenuId::new("toggle_mute"), "Toggle Mute", true, None);
+        let toggle_mute_item = command_item(TeamsCommand::ToggleMute, "Toggle Mute");
+        let toggle_video_item = command_item(TeamsCommand::ToggleVideo, "Toggle Video");
+        let toggle_hand_item = command_item(TeamsCommand::ToggleHand, "Raise/Lower Hand");
+        let toggle_blur_item =
+            command_item(TeamsCommand::ToggleBackgroundBlur, "Toggle Background Blur");
+        let stop_sharing_item = command_item(TeamsCommand::StopSharing, "Stop Sharing");
+        let leave_call_item = command_item(TeamsCommand::LeaveCall, "Leave Call");
+
+        let reactions_menu = Submenu::new("Send Reaction", true);
+        for reaction in Reaction::ALL {
+            let label = match reaction {
+                Reaction::Like => "Like",
+                Reaction::Love => "Love",
+                Reaction::Applause => "Applause",
+                Reaction::Laugh => "Laugh",
+                Reaction::Wow => "Wow",
+            };
+            reactions_menu
+                .append(&command_item(TeamsCommand::SendReaction(reaction), label))
+                .unwrap();
+        }
+
+        let pair_item = MenuItem::with_id(MenuId::new("pair_teams"), "Pair with Teams", true, None);
         let quit_item = MenuItem::with_id(MenuId::new("quit"), "Quit", true, None);
 
         // Create launch at startup menu item with current state
@@ -70,6 +100,14 @@ impl TrayWindows {
             MenuItem::with_id(MenuId::new("launch_startup"), launch_label, true, None);
 
         menu.append(&toggle_mute_item).unwrap();
+        menu.append(&toggle_video_item).unwrap();
+        menu.append(&toggle_hand_item).unwrap();
+        menu.append(&toggle_blur_item).unwrap();
+        menu.append(&reactions_menu).unwrap();
+        menu.append(&stop_sharing_item).unwrap();
+        menu.append(&leave_call_item).unwrap();
+        menu.append(&PredefinedMenuItem::separator()).unwrap();
+        menu.append(&pair_item).unwrap();
         menu.append(&launch_item).unwrap();
         menu.append(&quit_item).unwrap();
 
@@ -87,7 +125,7 @@ impl TrayWindows {
 
         TrayIconBuilder::new()
             .with_icon(icon)
-            .with_tooltip("Teams Status")
+            .with_tooltip(TOOLTIP)
             .with_menu(Box::new(menu))
             .build()
             .unwrap()
@@ -98,6 +136,35 @@ impl TrayWindows {
             let auto_launch = self.auto_launch.read().unwrap();
             self.tray = Self::create_tray_icon(&auto_launch);
             self.needs_recreation.store(false, Ordering::Relaxed);
+            self.refresh_tooltip();
+        }
+    }
+
+    pub fn show_command_outcome(&mut self, command: TeamsCommand, outcome: &CommandOutcome) {
+        self.command_error = match outcome {
+            CommandOutcome::Success => None,
+            _ => Some(format!("'{}' failed: {}", command.action(), outcome)),
+        };
+        self.refresh_tooltip();
+    }
+
+    pub fn show_connection_status(&mut self, connection_summary: &str) {
+        if self.connection_summary != connection_summary {
+            self.connection_summary = connection_summary.to_string();
+            self.refresh_tooltip();
+        }
+    }
+
+    fn refresh_tooltip(&self) {
+        // the command failure comes first, it would be cut off after a long connection summary
+        let lines = [
+            self.command_error.as_deref(),
+            Some(self.connection_summary.as_str()),
+        ];
+        let tooltip = tooltip_text(lines.into_iter().flatten());
+
+        if let Err(e) = self.tray.set_tooltip(Some(tooltip)) {
+            log::error!("Failed to update tray tooltip: {:?}", e);
         }
     }
 
@@ -141,10 +208,50 @@ impl StopController for Arc<TrayWindows> {
     }
 }
 
-pub fn create_tray(
-    is_running: Arc<AtomicBool>,
-    toggle_mute: Arc<AtomicBool>,
-) -> Box<dyn StopController> {
-    let tray = TrayWindows::new(is_running, toggle_mute);
+fn command_menu_id(command: TeamsCommand) -> String {
+    match command {
+        TeamsCommand::SendReaction(reaction) => {
+            format!("{}:{}", command.action(), reaction.as_str())
+        }
+        _ => command.action().to_string(),
+    }
+}
+
+fn tooltip_text<'a>(lines: impl Iterator<Item = &'a str>) -> String {
+    let mut tooltip = TOOLTIP.to_string();
+
+    for line in lines.filter(|line| !line.is_empty()) {
+        tooltip.push('\n');
+        tooltip.push_str(line);
+    }
+
+    if tooltip.encode_utf16().count() <= TOOLTIP_MAX_LENGTH {
+        return tooltip;
+    }
+
+    // the end is cut visibly, leaving room for the ellipsis
+    let mut length = 0;
+    let mut truncated: String = tooltip
+        .chars()
+        .take_while(|c| {
+            length += c.len_utf16();
+            length < TOOLTIP_MAX_LENGTH
+        })
+        .collect();
+    truncated.push('…');
+    truncated
+}
+
+fn command_item(command: TeamsCommand, label: &str) -> MenuItem {
+    MenuItem::with_id(MenuId::new(command_menu_id(command)), label, true, None)
+}
+
+pub fn command_from_menu_id(menu_id: &str) -> Option<TeamsCommand> {
+    let (action, parameter) = menu_id.split_once(':').unwrap_or((menu_id, ""));
+    TeamsCommand::parse(action, parameter)
+}
+
+pub fn create_tray() -> Box<dyn StopController> {
+    let tray = TrayWindows::new();
     Box::new(tray)
 }
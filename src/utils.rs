@@ -12,6 +12,17 @@ pub fn bool_to_str(bool: bool) -> String {
     };
 }
 
+pub fn flag_to_str(flag: Option<bool>) -> String {
+    match flag {
+        Some(value) => bool_to_str(value),
+        None => "unknown".to_string(),
+    }
+}
+
+pub fn computer_name() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "MyPC".to_string())
+}
+
 pub fn encrypt(value: &str) -> String {
     let mc = new_magic_crypt!(CRYPTO_KEY, 256);
 